@@ -0,0 +1,635 @@
+//! The x86-64 assembly backend: a GAS source file for Linux that needs no
+//! libc, only `as` and `ld`. The tape is a fixed block of `.bss`, `%rbx`
+//! points at the current cell, and I/O goes through raw `read`/`write`
+//! syscalls with a buffer for output.
+
+use super::{BfStr, Config, Eof, Op, OptReport, Overflow, Pipeline, Run};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// How many cells the fixed tape has.
+const TAPE_CELLS: usize = 1 << 20;
+
+/// The runtime every program links in. Its routines keep `%rbx` and
+/// `%r12`-`%r15`, and may change any other register.
+static RUNTIME: &str = concat!(
+    "    .section .rodata\n",
+    "error_msg: .ascii \"error: \"\n",
+    "at_msg: .ascii \" at \"\n",
+    "newline_msg: .ascii \"\\n\"\n",
+    "underflow_msg: .ascii \"tape underflow\"\n",
+    "end_msg: .ascii \"end of tape\"\n",
+    "overflow_msg: .ascii \"cell overflow\"\n",
+    "eof_msg: .ascii \"unexpected end of input\"\n",
+    "\n",
+    "    .bss\n",
+    "    .align 64\n",
+    "outbuf: .skip 4096\n",
+    "outlen: .skip 8\n",
+    "inbyte: .skip 1\n",
+    "\n",
+    "    .text\n",
+    "# write(%edi, %rsi, %rdx) until it's all written or fails\n",
+    "write_all:\n",
+    "    test %rdx, %rdx\n",
+    "    jz 1f\n",
+    "    mov $1, %eax\n",
+    "    syscall\n",
+    "    test %rax, %rax\n",
+    "    jle 1f\n",
+    "    add %rax, %rsi\n",
+    "    sub %rax, %rdx\n",
+    "    jmp write_all\n",
+    "1:  ret\n",
+    "\n",
+    "flush:\n",
+    "    mov $1, %edi\n",
+    "    lea outbuf(%rip), %rsi\n",
+    "    mov outlen(%rip), %rdx\n",
+    "    call write_all\n",
+    "    movq $0, outlen(%rip)\n",
+    "    ret\n",
+    "\n",
+    "# buffer the byte in %al\n",
+    "putbyte:\n",
+    "    mov outlen(%rip), %rcx\n",
+    "    lea outbuf(%rip), %rdx\n",
+    "    mov %al, (%rdx,%rcx)\n",
+    "    inc %rcx\n",
+    "    mov %rcx, outlen(%rip)\n",
+    "    cmp $4096, %rcx\n",
+    "    jb 1f\n",
+    "    call flush\n",
+    "1:  ret\n",
+    "\n",
+    "# put the byte in %dil %rsi times\n",
+    "putbytes:\n",
+    "    push %r12\n",
+    "    push %r13\n",
+    "    mov %rdi, %r12\n",
+    "    mov %rsi, %r13\n",
+    "1:  test %r13, %r13\n",
+    "    jz 2f\n",
+    "    mov %r12d, %eax\n",
+    "    call putbyte\n",
+    "    dec %r13\n",
+    "    jmp 1b\n",
+    "2:  pop %r13\n",
+    "    pop %r12\n",
+    "    ret\n",
+    "\n",
+    "# put the %rdx bytes at %rsi\n",
+    "putbuf:\n",
+    "    push %r12\n",
+    "    push %r13\n",
+    "    mov %rsi, %r12\n",
+    "    mov %rdx, %r13\n",
+    "1:  test %r13, %r13\n",
+    "    jz 2f\n",
+    "    movzbl (%r12), %eax\n",
+    "    call putbyte\n",
+    "    inc %r12\n",
+    "    dec %r13\n",
+    "    jmp 1b\n",
+    "2:  pop %r13\n",
+    "    pop %r12\n",
+    "    ret\n",
+    "\n",
+    "# the next input byte in %eax, or -1 at the end of input\n",
+    "getbyte:\n",
+    "    call flush\n",
+    "    xor %eax, %eax\n",
+    "    xor %edi, %edi\n",
+    "    lea inbyte(%rip), %rsi\n",
+    "    mov $1, %edx\n",
+    "    syscall\n",
+    "    cmp $1, %rax\n",
+    "    jne 1f\n",
+    "    movzbl inbyte(%rip), %eax\n",
+    "    ret\n",
+    "1:  mov $-1, %eax\n",
+    "    ret\n",
+    "\n",
+    "# print `error: <%rsi, %rdx> at <%r8, %r9>` and exit with status 3\n",
+    "fail:\n",
+    "    push %r8\n",
+    "    push %r9\n",
+    "    push %rsi\n",
+    "    push %rdx\n",
+    "    call flush\n",
+    "    mov $2, %edi\n",
+    "    lea error_msg(%rip), %rsi\n",
+    "    mov $7, %edx\n",
+    "    call write_all\n",
+    "    pop %rdx\n",
+    "    pop %rsi\n",
+    "    mov $2, %edi\n",
+    "    call write_all\n",
+    "    mov $2, %edi\n",
+    "    lea at_msg(%rip), %rsi\n",
+    "    mov $4, %edx\n",
+    "    call write_all\n",
+    "    pop %rdx\n",
+    "    pop %rsi\n",
+    "    mov $2, %edi\n",
+    "    call write_all\n",
+    "    mov $2, %edi\n",
+    "    lea newline_msg(%rip), %rsi\n",
+    "    mov $1, %edx\n",
+    "    call write_all\n",
+    "    mov $60, %eax\n",
+    "    mov $3, %edi\n",
+    "    syscall\n",
+    "\n",
+    "fail_underflow:\n",
+    "    lea underflow_msg(%rip), %rsi\n",
+    "    mov $14, %edx\n",
+    "    jmp fail\n",
+    "fail_end:\n",
+    "    lea end_msg(%rip), %rsi\n",
+    "    mov $11, %edx\n",
+    "    jmp fail\n",
+    "fail_overflow:\n",
+    "    lea overflow_msg(%rip), %rsi\n",
+    "    mov $13, %edx\n",
+    "    jmp fail\n",
+    "fail_eof:\n",
+    "    lea eof_msg(%rip), %rsi\n",
+    "    mov $23, %edx\n",
+    "    jmp fail\n",
+    "\n",
+    "    .globl _start\n",
+    "_start:\n",
+    "    lea tape(%rip), %rbx\n",
+);
+
+static PROGRAM_TAIL: &str = concat!(
+    "    call flush\n",
+    "    mov $60, %eax\n",
+    "    xor %edi, %edi\n",
+    "    syscall\n",
+);
+
+/// What one cell looks like in AT&T syntax for a given width.
+struct Width {
+    /// Bytes per cell.
+    size: usize,
+    /// Instruction suffix, e.g. `b` in `addb`.
+    suffix: char,
+    /// `%rcx` and `%rax` cut to the cell width.
+    rcx: &'static str,
+    rax: &'static str,
+    /// Loads a cell into `%rcx`, zero-extended.
+    load_rcx: &'static str,
+    /// Data directive for a cell.
+    data: &'static str,
+    max: u64,
+}
+
+impl Width {
+    fn new(bits: u32) -> Self {
+        match bits {
+            8 => Width {
+                size: 1,
+                suffix: 'b',
+                rcx: "%cl",
+                rax: "%al",
+                load_rcx: "movzbq",
+                data: ".byte",
+                max: u8::MAX as u64,
+            },
+            16 => Width {
+                size: 2,
+                suffix: 'w',
+                rcx: "%cx",
+                rax: "%ax",
+                load_rcx: "movzwq",
+                data: ".short",
+                max: u16::MAX as u64,
+            },
+            32 => Width {
+                size: 4,
+                suffix: 'l',
+                rcx: "%ecx",
+                rax: "%eax",
+                // a 32-bit move clears the upper half by itself
+                load_rcx: "movl",
+                data: ".long",
+                max: u32::MAX as u64,
+            },
+            _ => Width {
+                size: 8,
+                suffix: 'q',
+                rcx: "%rcx",
+                rax: "%rax",
+                load_rcx: "movq",
+                data: ".quad",
+                max: u64::MAX,
+            },
+        }
+    }
+
+    /// The destination for `load_rcx`, which is `%ecx` for 32-bit cells.
+    fn load_dst(&self) -> &'static str {
+        if self.size == 4 {
+            "%ecx"
+        } else {
+            "%rcx"
+        }
+    }
+}
+
+/// Emits the code for one op at a time, collecting the error stubs and
+/// read-only data that go after the program.
+struct Emitter<'a> {
+    config: &'a Config,
+    width: Width,
+    text: String,
+    stubs: String,
+    rodata: String,
+    /// The index of the op being emitted, which names its labels.
+    idx: usize,
+}
+
+impl Emitter<'_> {
+    fn line(&mut self, line: &str) {
+        self.text.push_str("    ");
+        self.text.push_str(line);
+        self.text.push('\n');
+    }
+
+    fn label(&mut self, label: &str) {
+        self.text.push_str(label);
+        self.text.push_str(":\n");
+    }
+
+    /// A label that jumps to `fail_<kind>` with this op's location, made on
+    /// first use.
+    fn fail(&mut self, kind: &str) -> String {
+        let label = format!(".Lfail_{}_{}", kind, self.idx);
+        let stub = format!("{}:\n", label);
+        if !self.stubs.contains(&stub) {
+            self.stubs.push_str(&stub);
+            self.stubs.push_str(&format!(
+                "    lea .Lloc_{idx}(%rip), %r8\n    mov $.Lloc_end_{idx} - .Lloc_{idx}, %r9d\n    jmp fail_{kind}\n",
+                idx = self.idx,
+                kind = kind
+            ));
+        }
+        label
+    }
+
+    /// Fail unless `%rdi` points into the tape.
+    fn check_rdi(&mut self) {
+        let underflow = self.fail("underflow");
+        let end = self.fail("end");
+        self.line("lea tape(%rip), %rax");
+        self.line("cmp %rax, %rdi");
+        self.line(&format!("jb {}", underflow));
+        self.line("lea tape_end(%rip), %rax");
+        self.line("cmp %rax, %rdi");
+        self.line(&format!("jae {}", end));
+    }
+
+    /// The address of `cell[ptr + offset]`, checked against the ends of
+    /// the tape unless it's the current cell, which always is in it.
+    fn cell(&mut self, offset: isize) -> String {
+        if offset == 0 {
+            return String::from("(%rbx)");
+        }
+        self.line(&format!(
+            "lea {}(%rbx), %rdi",
+            offset * self.width.size as isize
+        ));
+        self.check_rdi();
+        String::from("(%rdi)")
+    }
+
+    /// Move the pointer by `delta` cells.
+    fn shift(&mut self, delta: isize) {
+        self.line(&format!(
+            "lea {}(%rbx), %rdi",
+            delta * self.width.size as isize
+        ));
+        self.check_rdi();
+        self.line("mov %rdi, %rbx");
+    }
+
+    /// Add `magnitude` (in `%rax`, with the high half of a product in
+    /// `%rdx` if `wide`) to the cell at `cell`, or take it away if
+    /// `negative`, under the overflow policy. `%rax` may be anything for
+    /// 64-bit cells, so this works in 64-bit registers throughout.
+    fn checked_add(&mut self, cell: &str, negative: bool, wide: bool) {
+        let done = format!(".Ldone_{}", self.idx);
+        let out = match self.config.overflow {
+            Overflow::Trap => self.fail("overflow"),
+            _ => format!(".Lsaturate_{}", self.idx),
+        };
+        if wide {
+            self.line("test %rdx, %rdx");
+            self.line(&format!("jnz {}", out));
+        }
+        let load = format!(
+            "{} {}, {}",
+            self.width.load_rcx,
+            cell,
+            self.width.load_dst()
+        );
+        self.line(&load);
+        if negative {
+            self.line("sub %rax, %rcx");
+            self.line(&format!("jc {}", out));
+        } else {
+            self.line("add %rax, %rcx");
+            self.line(&format!("jc {}", out));
+            if self.width.size < 8 {
+                self.line(&format!("mov ${}, %rax", self.width.max));
+                self.line("cmp %rax, %rcx");
+                self.line(&format!("ja {}", out));
+            }
+        }
+        let store = format!("mov{} {}, {}", self.width.suffix, self.width.rcx, cell);
+        self.line(&store);
+        if self.config.overflow == Overflow::Saturate {
+            let bound = if negative { 0 } else { self.width.max };
+            self.line(&format!("jmp {}", done));
+            self.label(&out);
+            self.line(&format!("movabsq ${}, %rcx", bound));
+            self.line(&store);
+        }
+        self.label(&done);
+    }
+
+    fn update(&mut self, offset: isize, delta: isize) {
+        let cell = self.cell(offset);
+        if self.config.overflow == Overflow::Wrap {
+            // wrapping is what the CPU does anyway
+            let delta = (delta as i128).rem_euclid(self.width.max as i128 + 1) as u64;
+            self.line(&format!("movabsq ${}, %rax", delta));
+            let add = format!("add{} {}, {}", self.width.suffix, self.width.rax, cell);
+            self.line(&add);
+            return;
+        }
+        self.line(&format!("movabsq ${}, %rax", delta.unsigned_abs()));
+        self.checked_add(&cell, delta < 0, false);
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize) {
+        let done = format!(".Lskip_{}", self.idx);
+        let load = format!("{} (%rbx), {}", self.width.load_rcx, self.width.load_dst());
+        self.line(&load);
+        self.line("test %rcx, %rcx");
+        self.line(&format!("jz {}", done));
+        let cell = self.cell(offset);
+        self.line("mov %rcx, %rax");
+        if self.config.overflow == Overflow::Wrap {
+            self.line(&format!("movabsq ${}, %rcx", factor));
+            self.line("imulq %rcx, %rax");
+            let add = format!("add{} {}, {}", self.width.suffix, self.width.rax, cell);
+            self.line(&add);
+        } else {
+            self.line(&format!("movabsq ${}, %rcx", factor.unsigned_abs()));
+            self.line("mulq %rcx");
+            self.checked_add(&cell, factor < 0, true);
+        }
+        self.label(&done);
+    }
+
+    fn scan(&mut self, stride: isize) {
+        let suffix = self.width.suffix;
+        let shift = self.width.size.trailing_zeros();
+        match stride {
+            // `repne scas` compares from `%rdi` on, for at most `%rcx`
+            // cells, and stops one cell past the zero it finds
+            1 => {
+                let end = self.fail("end");
+                self.line("mov %rbx, %rdi");
+                self.line("lea tape_end(%rip), %rcx");
+                self.line("sub %rdi, %rcx");
+                self.line(&format!("shr ${}, %rcx", shift));
+                self.line("xor %eax, %eax");
+                self.line(&format!("repne scas{}", suffix));
+                self.line(&format!("jne {}", end));
+                self.line(&format!("lea -{}(%rdi), %rbx", self.width.size));
+            }
+            -1 => {
+                let underflow = self.fail("underflow");
+                self.line("mov %rbx, %rdi");
+                self.line("lea tape(%rip), %rcx");
+                self.line("mov %rbx, %rax");
+                self.line("sub %rcx, %rax");
+                self.line(&format!("shr ${}, %rax", shift));
+                self.line("lea 1(%rax), %rcx");
+                self.line("xor %eax, %eax");
+                self.line("std");
+                self.line(&format!("repne scas{}", suffix));
+                self.line("cld");
+                self.line(&format!("jne {}", underflow));
+                self.line(&format!("lea {}(%rdi), %rbx", self.width.size));
+            }
+            _ => {
+                let head = format!(".Lscan_{}", self.idx);
+                let done = format!(".Ldone_{}", self.idx);
+                self.label(&head);
+                self.line(&format!("cmp{} $0, (%rbx)", suffix));
+                self.line(&format!("je {}", done));
+                self.shift(stride);
+                self.line(&format!("jmp {}", head));
+                self.label(&done);
+            }
+        }
+    }
+
+    fn input(&mut self, offset: isize) {
+        let eof = format!(".Leof_{}", self.idx);
+        let done = format!(".Ldone_{}", self.idx);
+        // check the cell before reading, then find it again since
+        // `getbyte` changes `%rdi`
+        self.cell(offset);
+        self.line("call getbyte");
+        self.line("cmp $-1, %eax");
+        self.line(&format!("je {}", eof));
+        let cell = self.address(offset);
+        self.line(&format!(
+            "mov{} {}, {}",
+            self.width.suffix, self.width.rax, cell
+        ));
+        self.line(&format!("jmp {}", done));
+        self.label(&eof);
+        match self.config.eof {
+            Eof::Unchanged => (),
+            Eof::Zero | Eof::MinusOne => {
+                let value = if self.config.eof == Eof::Zero { 0 } else { -1 };
+                let cell = self.address(offset);
+                self.line(&format!("mov{} ${}, {}", self.width.suffix, value, cell));
+            }
+            Eof::Error => {
+                let fail = self.fail("eof");
+                self.line(&format!("jmp {}", fail));
+            }
+        }
+        self.label(&done);
+    }
+
+    /// The address of `cell[ptr + offset]` without checking it again.
+    fn address(&mut self, offset: isize) -> String {
+        if offset == 0 {
+            return String::from("(%rbx)");
+        }
+        self.line(&format!(
+            "lea {}(%rbx), %rdi",
+            offset * self.width.size as isize
+        ));
+        String::from("(%rdi)")
+    }
+
+    fn output(&mut self, offset: isize, count: isize) {
+        let cell = self.cell(offset);
+        // the low byte comes first
+        self.line(&format!("movzbl {}, %edi", cell));
+        self.line(&format!("mov ${}, %esi", count));
+        self.line("call putbytes");
+    }
+
+    fn set(&mut self, offset: isize, value: isize) {
+        let cell = self.cell(offset);
+        self.line(&format!("movabsq ${}, %rax", value));
+        self.line(&format!(
+            "mov{} {}, {}",
+            self.width.suffix, self.width.rax, cell
+        ));
+    }
+
+    fn preset(&mut self, output: &[u8], runs: &[Run], ptr: usize, last: bool) -> io::Result<()> {
+        let idx = self.idx;
+        if !output.is_empty() {
+            let bytes: Vec<String> = output.iter().map(|byte| byte.to_string()).collect();
+            self.rodata
+                .push_str(&format!(".Loutput_{}: .byte {}\n", idx, bytes.join(", ")));
+            self.line(&format!("lea .Loutput_{}(%rip), %rsi", idx));
+            self.line(&format!("mov ${}, %edx", output.len()));
+            self.line("call putbuf");
+        }
+        // nobody can look at the tape once the program ends
+        if last {
+            return Ok(());
+        }
+        if runs.last().map_or(0, Run::end) > TAPE_CELLS || ptr >= TAPE_CELLS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the program needs more than {} cells", TAPE_CELLS),
+            ));
+        }
+        for (n, run) in runs.iter().enumerate() {
+            let values: Vec<String> = run.cells.iter().map(|cell| cell.to_string()).collect();
+            self.rodata.push_str(&format!(
+                ".Lcells_{}_{}: {} {}\n",
+                idx,
+                n,
+                self.width.data,
+                values.join(", ")
+            ));
+            self.line(&format!(
+                "lea tape+{}(%rip), %rdi",
+                run.start * self.width.size
+            ));
+            self.line(&format!("lea .Lcells_{}_{}(%rip), %rsi", idx, n));
+            self.line(&format!("mov ${}, %ecx", run.cells.len() * self.width.size));
+            self.line("rep movsb");
+        }
+        self.line("lea tape(%rip), %rbx");
+        self.line(&format!("add ${}, %rbx", ptr * self.width.size));
+        Ok(())
+    }
+}
+
+/// `text` as the contents of a GAS `.ascii` string.
+fn asm_string(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl BfStr {
+    fn _asm(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let (ops, report) = self.optimize_report(pipeline, config);
+        let width = Width::new(config.cell_bits.bits());
+        let mut emitter = Emitter {
+            config,
+            width,
+            text: String::new(),
+            stubs: String::new(),
+            rodata: String::new(),
+            idx: 0,
+        };
+        for (idx, op) in ops.iter().enumerate() {
+            emitter.idx = idx;
+            emitter.text.push_str(&format!("    # {}\n", op));
+            emitter.rodata.push_str(&format!(
+                ".Lloc_{idx}: .ascii \"{}\"\n.Lloc_end_{idx}:\n",
+                asm_string(&format!("line {}, column {}", op.span.line, op.span.column)),
+                idx = idx
+            ));
+            let suffix = emitter.width.suffix;
+            match op.operator {
+                Op::Upd => emitter.update(op.offset, op.operand),
+                Op::Shf => emitter.shift(op.operand),
+                Op::Scan { stride } => emitter.scan(stride),
+                Op::Acp => emitter.input(op.offset),
+                Op::Out => emitter.output(op.offset, op.operand),
+                Op::Jpf => {
+                    emitter.line(&format!("cmp{} $0, (%rbx)", suffix));
+                    emitter.line(&format!("je .Lend_{}", idx));
+                    emitter.label(&format!(".Lbody_{}", idx));
+                }
+                Op::Jpb => {
+                    let jpf = op.operand - 1;
+                    emitter.line(&format!("cmp{} $0, (%rbx)", suffix));
+                    emitter.line(&format!("jne .Lbody_{}", jpf));
+                    emitter.label(&format!(".Lend_{}", jpf));
+                }
+                Op::Set => emitter.set(op.offset, op.operand),
+                Op::MulAdd { offset, factor } => emitter.mul_add(offset, factor),
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => emitter.preset(output, runs, ptr, idx + 1 == ops.len())?,
+            }
+        }
+        writeln!(
+            write,
+            "# cells: {} bits, {} of them",
+            config.cell_bits, TAPE_CELLS
+        )?;
+        writeln!(write, "    .bss")?;
+        writeln!(write, "    .align 64")?;
+        writeln!(
+            write,
+            "tape: .skip {}\ntape_end:\n",
+            TAPE_CELLS * emitter.width.size
+        )?;
+        write!(write, "{}", RUNTIME)?;
+        write!(write, "{}", emitter.text)?;
+        write!(write, "{}", PROGRAM_TAIL)?;
+        write!(write, "\n{}", emitter.stubs)?;
+        write!(write, "\n    .section .rodata\n{}", emitter.rodata)?;
+        Ok(report)
+    }
+
+    pub fn asm(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let file = File::create(save_path)?;
+        self._asm(file, pipeline, config)
+    }
+}
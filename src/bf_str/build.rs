@@ -0,0 +1,195 @@
+//! `build`: compile to C in a temporary directory and hand it to the
+//! system C compiler, so a program becomes an executable in one step.
+
+use super::{BfStr, CcOptions, Config, OptReport, Pipeline};
+use std::{
+    collections::hash_map::RandomState,
+    env,
+    error::Error,
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+/// The compilers tried in order when `$CC` isn't set.
+const COMPILERS: [&str; 3] = ["cc", "gcc", "clang"];
+
+/// How `build` turns the generated C into an executable.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// How to write the C.
+    pub cc: CcOptions,
+    /// Passed to the C compiler before the source file, e.g. `-O2`.
+    pub cflags: Vec<String>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            cc: CcOptions::default(),
+            cflags: vec![String::from("-O2")],
+        }
+    }
+}
+
+/// Why `build` didn't produce an executable.
+#[derive(Debug)]
+pub enum BuildError {
+    /// Writing the C or starting the compiler failed.
+    Io(io::Error),
+    /// None of `$CC`, `cc`, `gcc` or `clang` could be run.
+    NoCompiler,
+    /// The compiler ran and failed. The C it was given is left at `c_file`
+    /// to look into.
+    Compiler {
+        compiler: String,
+        status: ExitStatus,
+        c_file: PathBuf,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io(err) => write!(f, "{}", err),
+            BuildError::NoCompiler => write!(
+                f,
+                "no C compiler found, set $CC or install one of {}",
+                COMPILERS.join(", ")
+            ),
+            BuildError::Compiler {
+                compiler,
+                status,
+                c_file,
+            } => write!(
+                f,
+                "`{}` failed with {}, the generated C is kept at {}",
+                compiler,
+                status,
+                c_file.display()
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+/// Whether `program` starts at all.
+fn runs(program: &str) -> bool {
+    Command::new(program)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// The compiler to use and any arguments that come with it: `$CC` split on
+/// whitespace, like `make` does, or else the first of [`COMPILERS`] found.
+fn find_compiler() -> Result<Vec<String>, BuildError> {
+    if let Some(cc) = env::var_os("CC") {
+        let cc: Vec<String> = cc
+            .to_string_lossy()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        if !cc.is_empty() {
+            return Ok(cc);
+        }
+    }
+    COMPILERS
+        .iter()
+        .find(|compiler| runs(compiler))
+        .map(|compiler| vec![compiler.to_string()])
+        .ok_or(BuildError::NoCompiler)
+}
+
+/// A fresh directory for one build's C file, that only we can get into.
+fn temp_dir() -> io::Result<PathBuf> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    // a name nobody can guess and make first, and never one that's there
+    // already, so nobody else decides what ends up in the C file
+    loop {
+        let suffix = RandomState::new().build_hasher().finish();
+        let dir = env::temp_dir().join(format!("bfter-{:016x}", suffix));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+impl BfStr {
+    /// Compile to C and build it into an executable at `save_path` with the
+    /// C compiler from `$CC`, or else `cc`, `gcc` or `clang`.
+    pub fn build(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+        options: &BuildOptions,
+    ) -> Result<OptReport, BuildError> {
+        let mut compiler = find_compiler()?;
+        let program = compiler.remove(0);
+        let dir = temp_dir()?;
+        // named after the executable, for the compiler's diagnostics
+        let mut name = save_path
+            .file_name()
+            .unwrap_or("main".as_ref())
+            .to_os_string();
+        name.push(".c");
+        let c_file = dir.join(name);
+        let built = self
+            .cc(&c_file, pipeline, config, &options.cc)
+            .and_then(|report| {
+                // the compiler's own diagnostics go straight to our stderr
+                Command::new(&program)
+                    .args(&compiler)
+                    .args(&options.cflags)
+                    .arg("-o")
+                    .arg(save_path)
+                    .arg(&c_file)
+                    .status()
+                    .map_err(|err| {
+                        io::Error::new(err.kind(), format!("can't run `{}`: {}", program, err))
+                    })
+                    .map(|status| (report, status))
+            });
+        match built {
+            Ok((_, status)) if !status.success() => Err(BuildError::Compiler {
+                compiler: program,
+                status,
+                c_file,
+            }),
+            Ok((report, _)) => {
+                fs::remove_dir_all(&dir)?;
+                Ok(report)
+            }
+            Err(err) => {
+                let _ = fs::remove_dir_all(&dir);
+                Err(err.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::{fmt, str::FromStr};
+
+/// Width of a tape cell.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CellBits {
+    #[default]
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl CellBits {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellBits::U8 => 8,
+            CellBits::U16 => 16,
+            CellBits::U32 => 32,
+            CellBits::U64 => 64,
+        }
+    }
+
+    /// The matching fixed-width C type, e.g. `uint16_t`.
+    pub fn c_type(self) -> String {
+        format!("uint{}_t", self.bits())
+    }
+
+    /// The matching LLVM integer type, e.g. `i16`.
+    pub fn llvm_type(self) -> String {
+        format!("i{}", self.bits())
+    }
+}
+
+impl FromStr for CellBits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellBits::U8),
+            "16" => Ok(CellBits::U16),
+            "32" => Ok(CellBits::U32),
+            "64" => Ok(CellBits::U64),
+            _ => Err(format!(
+                "unsupported cell width `{}`, expect 8, 16, 32 or 64",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CellBits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+/// What happens when an update takes a cell out of `0..=MAX`.
+///
+/// Runs of `+`/`-` are folded into one op, so the policy applies to the net
+/// change of each op rather than to every single step.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    /// Arithmetic modulo `2^BITS`.
+    #[default]
+    Wrap,
+    /// Clamp to `0` or `MAX`.
+    Saturate,
+    /// Stop with an error.
+    Trap,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            _ => Err(format!(
+                "unknown overflow policy `{}`, expect wrap, saturate or trap",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overflow::Wrap => write!(f, "wrap"),
+            Overflow::Saturate => write!(f, "saturate"),
+            Overflow::Trap => write!(f, "trap"),
+        }
+    }
+}
+
+/// What `,` does when there is no more input.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Eof {
+    /// Leave the cell as it was.
+    #[default]
+    Unchanged,
+    /// Store 0.
+    Zero,
+    /// Store -1, i.e. `MAX` of the cell width.
+    MinusOne,
+    /// Stop with an error.
+    Error,
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(Eof::Unchanged),
+            "zero" => Ok(Eof::Zero),
+            "minus-one" => Ok(Eof::MinusOne),
+            "error" => Ok(Eof::Error),
+            _ => Err(format!(
+                "unknown EOF behaviour `{}`, expect unchanged, zero, minus-one or error",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Eof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Eof::Unchanged => write!(f, "unchanged"),
+            Eof::Zero => write!(f, "zero"),
+            Eof::MinusOne => write!(f, "minus-one"),
+            Eof::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// An unsigned tape cell. Plain arithmetic wraps modulo `2^BITS`, the same as
+/// the unsigned `uintN_t` arithmetic of the C backend.
+pub trait Cell: Copy + Default + PartialEq + fmt::Debug {
+    const MAX: i128;
+
+    /// `value` modulo `2^BITS`.
+    fn from_i128(value: i128) -> Self;
+    fn to_i128(self) -> i128;
+    fn from_byte(byte: u8) -> Self;
+    /// The low 8 bits, which is what `.` writes.
+    fn to_byte(self) -> u8;
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+
+    /// Add `delta` under the given overflow policy, or `None` if it traps.
+    fn add_with(self, delta: i128, overflow: Overflow) -> Option<Self> {
+        let value = self.to_i128().saturating_add(delta);
+        if (0..=Self::MAX).contains(&value) {
+            return Some(Self::from_i128(value));
+        }
+        match overflow {
+            Overflow::Wrap => Some(Self::from_i128(value)),
+            Overflow::Saturate => Some(Self::from_i128(value.clamp(0, Self::MAX))),
+            Overflow::Trap => None,
+        }
+    }
+
+    /// Add `factor * src` under the given overflow policy, or `None` if it
+    /// traps.
+    fn mul_add_with(self, src: Self, factor: i128, overflow: Overflow) -> Option<Self> {
+        let product = match overflow {
+            Overflow::Wrap => src.to_i128().wrapping_mul(factor),
+            _ => src.to_i128().saturating_mul(factor),
+        };
+        self.add_with(product, overflow)
+    }
+}
+
+macro_rules! impl_cell {
+    ($($ty:ty),*) => {
+        $(
+            impl Cell for $ty {
+                const MAX: i128 = <$ty>::MAX as i128;
+
+                fn from_i128(value: i128) -> Self {
+                    value as $ty
+                }
+
+                fn to_i128(self) -> i128 {
+                    self as i128
+                }
+
+                fn from_byte(byte: u8) -> Self {
+                    byte as $ty
+                }
+
+                fn to_byte(self) -> u8 {
+                    self as u8
+                }
+            }
+        )*
+    };
+}
+
+impl_cell!(u8, u16, u32, u64);
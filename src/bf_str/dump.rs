@@ -0,0 +1,157 @@
+//! Readable listings of the ops, for `dump-ir` and `--print-after-all`.
+
+use super::{Op, Operation};
+use std::{fmt, str::FromStr};
+
+/// How `dump-ir` prints the ops.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IrFormat {
+    /// One op per line in assembly style.
+    #[default]
+    Text,
+    /// A JSON array with one object per op, one per line.
+    Json,
+}
+
+impl FromStr for IrFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(IrFormat::Text),
+            "json" => Ok(IrFormat::Json),
+            _ => Err(format!("unknown format `{}`, expect text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for IrFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrFormat::Text => write!(f, "text"),
+            IrFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl Op {
+    fn mnemonic(&self) -> &'static str {
+        match self {
+            Op::Upd => "upd",
+            Op::Acp => "acp",
+            Op::Out => "out",
+            Op::Shf => "shf",
+            Op::Jpf => "jpf",
+            Op::Jpb => "jpb",
+            Op::Set => "set",
+            Op::MulAdd { .. } => "muladd",
+            Op::Scan { .. } => "scan",
+            Op::Preset { .. } => "preset",
+        }
+    }
+}
+
+/// One op in assembly style, e.g. `upd [+2] -3` for `cell[ptr + 2] -= 3`, or
+/// `jpf 7` for a `[` whose `]` is op 7.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.operator.mnemonic();
+        let cell = format!("[{:+}]", self.offset);
+        match &self.operator {
+            Op::Upd => write!(f, "{} {} {:+}", name, cell, self.operand),
+            Op::Set => write!(f, "{} {} {}", name, cell, self.operand),
+            Op::Acp => write!(f, "{} {}", name, cell),
+            Op::Out => write!(f, "{} {} x{}", name, cell, self.operand),
+            Op::Shf => write!(f, "{} {:+}", name, self.operand),
+            Op::Jpf | Op::Jpb => write!(f, "{} {}", name, self.operand - 1),
+            Op::MulAdd { offset, factor } => write!(f, "{} [{:+}] *{}", name, offset, factor),
+            Op::Scan { stride } => write!(f, "{} {:+}", name, stride),
+            Op::Preset { output, runs, ptr } => {
+                write!(f, "{} {:?}", name, String::from_utf8_lossy(output))?;
+                for run in runs {
+                    write!(f, " @{}={:?}", run.start, run.cells)?;
+                }
+                write!(f, " ptr={}", ptr)
+            }
+        }
+    }
+}
+
+/// `ops` in the given format.
+pub(super) fn dump(ops: &[Operation], format: IrFormat) -> String {
+    match format {
+        IrFormat::Text => disassemble(ops),
+        IrFormat::Json => json(ops),
+    }
+}
+
+/// A listing of `ops`, one per line with its index and source position.
+pub(super) fn disassemble(ops: &[Operation]) -> String {
+    let mut listing = String::new();
+    for (idx, op) in ops.iter().enumerate() {
+        listing.push_str(&format!(
+            "{:>6}  {:<24} ; {}\n",
+            idx,
+            op.to_string(),
+            op.span
+        ));
+    }
+    listing
+}
+
+/// `ops` as a JSON array. Every object has the `index`, `op` and `span` of
+/// the op, plus the operands that op has, e.g. `target` for the index of the
+/// other bracket of a jump.
+fn json(ops: &[Operation]) -> String {
+    let mut lines: Vec<String> = Vec::with_capacity(ops.len());
+    for (idx, op) in ops.iter().enumerate() {
+        let mut fields = vec![
+            format!("\"index\": {}", idx),
+            format!("\"op\": \"{}\"", op.operator.mnemonic()),
+        ];
+        match &op.operator {
+            Op::Upd => fields.extend([
+                format!("\"offset\": {}", op.offset),
+                format!("\"delta\": {}", op.operand),
+            ]),
+            Op::Acp => fields.push(format!("\"offset\": {}", op.offset)),
+            Op::Out => fields.extend([
+                format!("\"offset\": {}", op.offset),
+                format!("\"count\": {}", op.operand),
+            ]),
+            Op::Shf => fields.push(format!("\"delta\": {}", op.operand)),
+            Op::Jpf | Op::Jpb => fields.push(format!("\"target\": {}", op.operand - 1)),
+            Op::Set => fields.extend([
+                format!("\"offset\": {}", op.offset),
+                format!("\"value\": {}", op.operand),
+            ]),
+            Op::MulAdd { offset, factor } => fields.extend([
+                format!("\"offset\": {}", offset),
+                format!("\"factor\": {}", factor),
+            ]),
+            Op::Scan { stride } => fields.push(format!("\"stride\": {}", stride)),
+            // output as bytes, since it needn't be valid UTF-8
+            Op::Preset { output, runs, ptr } => {
+                let runs: Vec<String> = runs
+                    .iter()
+                    .map(|run| format!("{{\"start\": {}, \"cells\": {:?}}}", run.start, run.cells))
+                    .collect();
+                fields.extend([
+                    format!("\"output\": {:?}", output),
+                    format!("\"runs\": [{}]", runs.join(", ")),
+                    format!("\"ptr\": {}", ptr),
+                ])
+            }
+        }
+        let span = op.span;
+        fields.push(format!(
+            "\"span\": {{\"start\": {}, \"end\": {}, \"line\": {}, \"column\": {}}}",
+            span.start, span.end, span.line, span.column
+        ));
+        lines.push(format!("  {{{}}}", fields.join(", ")));
+    }
+    if lines.is_empty() {
+        return String::from("[]\n");
+    }
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
@@ -0,0 +1,837 @@
+//! The ELF backend: a static x86-64 Linux executable written byte by byte,
+//! with no assembler or linker involved. The code follows the assembly
+//! backend, with `%rbx` pointing at the current cell of a fixed tape, but
+//! encodes every instruction itself and addresses memory absolutely, since
+//! the program always loads at the same place.
+
+use super::{BfStr, Config, Eof, Op, OptReport, Overflow, Pipeline, Run};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// How many cells the fixed tape has.
+const TAPE_CELLS: usize = 1 << 20;
+
+/// Where the file, and so the code right after the headers, is loaded.
+const BASE: u64 = 0x40_0000;
+const HEADERS: u64 = 64 + 2 * 56;
+/// Where the zeroed memory for the output buffer and tape starts.
+const BSS: u64 = 0x1_0000_0000;
+const OUTBUF: u64 = BSS;
+const OUTLEN: u64 = BSS + 4096;
+const INBYTE: u64 = OUTLEN + 8;
+const TAPE: u64 = BSS + 4160;
+
+type Reg = u8;
+const RAX: Reg = 0;
+const RCX: Reg = 1;
+const RDX: Reg = 2;
+const RBX: Reg = 3;
+const RSI: Reg = 6;
+const RDI: Reg = 7;
+const R8: Reg = 8;
+const R9: Reg = 9;
+const R12: Reg = 12;
+const R13: Reg = 13;
+
+/// Condition codes, the low nibble of `jcc`.
+const B: u8 = 0x2;
+const AE: u8 = 0x3;
+const E: u8 = 0x4;
+const NE: u8 = 0x5;
+const A: u8 = 0x7;
+const LE: u8 = 0xe;
+
+#[derive(Debug, Clone, Copy)]
+struct Label(usize);
+
+enum Fixup {
+    /// A 32-bit displacement from the end of the field.
+    Rel32,
+    /// A 64-bit absolute address.
+    Abs64,
+}
+
+/// Machine code with labels that are resolved once everything is placed.
+#[derive(Default)]
+struct Code {
+    bytes: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Label, Fixup)>,
+}
+
+impl Code {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.bytes.len());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn fixup(&mut self, label: Label, fixup: Fixup) {
+        let size = match fixup {
+            Fixup::Rel32 => 4,
+            Fixup::Abs64 => 8,
+        };
+        self.fixups.push((self.bytes.len(), label, fixup));
+        self.bytes.resize(self.bytes.len() + size, 0);
+    }
+
+    fn jmp(&mut self, label: Label) {
+        self.emit(&[0xe9]);
+        self.fixup(label, Fixup::Rel32);
+    }
+
+    fn jcc(&mut self, cc: u8, label: Label) {
+        self.emit(&[0x0f, 0x80 | cc]);
+        self.fixup(label, Fixup::Rel32);
+    }
+
+    fn call(&mut self, label: Label) {
+        self.emit(&[0xe8]);
+        self.fixup(label, Fixup::Rel32);
+    }
+
+    /// `mov $imm, %reg` with a full 64-bit immediate.
+    fn mov_imm(&mut self, reg: Reg, imm: u64) {
+        self.emit(&[0x48 | reg >> 3, 0xb8 + (reg & 7)]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// `mov $imm, %reg32`, which clears the upper half.
+    fn mov_imm32(&mut self, reg: Reg, imm: u32) {
+        self.emit(&[0xb8 + reg]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// `mov $label, %reg`
+    fn mov_label(&mut self, reg: Reg, label: Label) {
+        self.emit(&[0x48 | reg >> 3, 0xb8 + (reg & 7)]);
+        self.fixup(label, Fixup::Abs64);
+    }
+
+    /// A 64-bit `op %src, %dst` like `add`, `sub`, `cmp`, `test` or `mov`.
+    fn rr(&mut self, op: u8, dst: Reg, src: Reg) {
+        self.emit(&[
+            0x48 | (src >> 3) << 2 | dst >> 3,
+            op,
+            0xc0 | (src & 7) << 3 | (dst & 7),
+        ]);
+    }
+
+    /// `lea disp(%base), %dst`
+    fn lea(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.emit(&[0x48, 0x8d, 0x80 | dst << 3 | base]);
+        self.emit(&disp.to_le_bytes());
+    }
+
+    /// An instruction on a `size`-byte cell at `(%base)`, with `op` its byte
+    /// form like `0x88` for `mov %reg, (%base)`.
+    fn mem(&mut self, size: usize, op: u8, reg: Reg, base: Reg) {
+        match size {
+            1 => self.emit(&[op]),
+            2 => self.emit(&[0x66, op + 1]),
+            4 => self.emit(&[op + 1]),
+            _ => self.emit(&[0x48, op + 1]),
+        }
+        self.emit(&[reg << 3 | base]);
+    }
+
+    /// Store the low `size` bytes of `reg` to `(%base)`.
+    fn store(&mut self, size: usize, reg: Reg, base: Reg) {
+        self.mem(size, 0x88, reg, base);
+    }
+
+    /// Load the cell at `(%base)` into `reg`, zero-extended.
+    fn load(&mut self, size: usize, reg: Reg, base: Reg) {
+        match size {
+            1 => self.emit(&[0x0f, 0xb6, reg << 3 | base]),
+            2 => self.emit(&[0x0f, 0xb7, reg << 3 | base]),
+            _ => self.mem(size, 0x8a, reg, base),
+        }
+    }
+
+    /// `cmp $0, (%base)` on a cell.
+    fn cmp_zero(&mut self, size: usize, base: Reg) {
+        match size {
+            1 => self.emit(&[0x80]),
+            2 => self.emit(&[0x66, 0x83]),
+            4 => self.emit(&[0x83]),
+            _ => self.emit(&[0x48, 0x83]),
+        }
+        self.emit(&[7 << 3 | base, 0]);
+    }
+
+    /// `repne scas` over cells, comparing with `%al`/`%ax`/`%eax`/`%rax`.
+    fn repne_scas(&mut self, size: usize) {
+        match size {
+            1 => self.emit(&[0xf2, 0xae]),
+            2 => self.emit(&[0xf2, 0x66, 0xaf]),
+            4 => self.emit(&[0xf2, 0xaf]),
+            _ => self.emit(&[0xf2, 0x48, 0xaf]),
+        }
+    }
+
+    /// Fill in the labels, with the code loaded at `address`.
+    fn link(mut self, address: u64) -> Vec<u8> {
+        for (at, label, fixup) in &self.fixups {
+            let target = self.labels[label.0].expect("label is never bound");
+            match fixup {
+                Fixup::Rel32 => {
+                    let rel = target as i64 - (*at as i64 + 4);
+                    self.bytes[*at..*at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+                }
+                Fixup::Abs64 => {
+                    let abs = address + target as u64;
+                    self.bytes[*at..*at + 8].copy_from_slice(&abs.to_le_bytes());
+                }
+            }
+        }
+        self.bytes
+    }
+}
+
+/// The runtime routines, which keep `%rbx` and `%r12`-`%r15` and may
+/// change any other register.
+struct Runtime {
+    flush: Label,
+    putbytes: Label,
+    putbuf: Label,
+    getbyte: Label,
+    fail_underflow: Label,
+    fail_end: Label,
+    fail_overflow: Label,
+    fail_eof: Label,
+}
+
+impl Runtime {
+    fn new(code: &mut Code) -> Self {
+        Runtime {
+            flush: code.label(),
+            putbytes: code.label(),
+            putbuf: code.label(),
+            getbyte: code.label(),
+            fail_underflow: code.label(),
+            fail_end: code.label(),
+            fail_overflow: code.label(),
+            fail_eof: code.label(),
+        }
+    }
+
+    fn emit(&self, code: &mut Code) {
+        const SYSCALL: [u8; 2] = [0x0f, 0x05];
+        const RET: [u8; 1] = [0xc3];
+
+        // write(%edi, %rsi, %rdx) until it's all written or fails
+        let write_all = code.label();
+        let done = code.label();
+        code.bind(write_all);
+        code.rr(0x85, RDX, RDX);
+        code.jcc(E, done);
+        code.mov_imm32(RAX, 1);
+        code.emit(&SYSCALL);
+        code.rr(0x85, RAX, RAX);
+        code.jcc(LE, done);
+        code.rr(0x01, RSI, RAX);
+        code.rr(0x29, RDX, RAX);
+        code.jmp(write_all);
+        code.bind(done);
+        code.emit(&RET);
+
+        code.bind(self.flush);
+        code.mov_imm32(RDI, 1);
+        code.mov_imm(RSI, OUTBUF);
+        code.mov_imm(RAX, OUTLEN);
+        // mov (%rax), %rdx
+        code.emit(&[0x48, 0x8b, 0x10]);
+        code.call(write_all);
+        code.mov_imm(RAX, OUTLEN);
+        code.rr(0x31, RCX, RCX);
+        // mov %rcx, (%rax)
+        code.emit(&[0x48, 0x89, 0x08]);
+        code.emit(&RET);
+
+        // buffer the byte in %al
+        let putbyte = code.label();
+        let done = code.label();
+        code.bind(putbyte);
+        code.mov_imm(RDX, OUTLEN);
+        // mov (%rdx), %rcx
+        code.emit(&[0x48, 0x8b, 0x0a]);
+        code.mov_imm(RSI, OUTBUF);
+        // mov %al, (%rsi,%rcx)
+        code.emit(&[0x88, 0x04, 0x0e]);
+        // inc %rcx
+        code.emit(&[0x48, 0xff, 0xc1]);
+        // mov %rcx, (%rdx)
+        code.emit(&[0x48, 0x89, 0x0a]);
+        // cmp $4096, %rcx
+        code.emit(&[0x48, 0x81, 0xf9, 0x00, 0x10, 0x00, 0x00]);
+        code.jcc(B, done);
+        code.call(self.flush);
+        code.bind(done);
+        code.emit(&RET);
+
+        // put the byte in %dil %rsi times, or the %rdx bytes at %rsi
+        for (entry, from_buf) in [(self.putbytes, false), (self.putbuf, true)] {
+            let head = code.label();
+            let done = code.label();
+            code.bind(entry);
+            // push %r12, push %r13
+            code.emit(&[0x41, 0x54, 0x41, 0x55]);
+            if from_buf {
+                code.rr(0x89, R12, RSI);
+                code.rr(0x89, R13, RDX);
+            } else {
+                code.rr(0x89, R12, RDI);
+                code.rr(0x89, R13, RSI);
+            }
+            code.bind(head);
+            code.rr(0x85, R13, R13);
+            code.jcc(E, done);
+            if from_buf {
+                // movzbl (%r12), %eax
+                code.emit(&[0x41, 0x0f, 0xb6, 0x04, 0x24]);
+            } else {
+                code.rr(0x89, RAX, R12);
+            }
+            code.call(putbyte);
+            if from_buf {
+                // inc %r12
+                code.emit(&[0x49, 0xff, 0xc4]);
+            }
+            // dec %r13
+            code.emit(&[0x49, 0xff, 0xcd]);
+            code.jmp(head);
+            code.bind(done);
+            // pop %r13, pop %r12
+            code.emit(&[0x41, 0x5d, 0x41, 0x5c]);
+            code.emit(&RET);
+        }
+
+        // the next input byte in %eax, or -1 at the end of input
+        let eof = code.label();
+        code.bind(self.getbyte);
+        code.call(self.flush);
+        code.mov_imm32(RAX, 0);
+        code.mov_imm32(RDI, 0);
+        code.mov_imm(RSI, INBYTE);
+        code.mov_imm32(RDX, 1);
+        code.emit(&SYSCALL);
+        // cmp $1, %rax
+        code.emit(&[0x48, 0x83, 0xf8, 0x01]);
+        code.jcc(NE, eof);
+        code.mov_imm(RSI, INBYTE);
+        // movzbl (%rsi), %eax
+        code.emit(&[0x0f, 0xb6, 0x06]);
+        code.emit(&RET);
+        code.bind(eof);
+        code.mov_imm32(RAX, u32::MAX);
+        code.emit(&RET);
+
+        // print `error: <%rsi, %rdx> at <%r8, %r9>` and exit with status 3
+        let fail = code.label();
+        let error_msg = code.label();
+        let at_msg = code.label();
+        let newline_msg = code.label();
+        code.bind(fail);
+        // push %r8, push %r9, push %rsi, push %rdx
+        code.emit(&[0x41, 0x50, 0x41, 0x51, 0x56, 0x52]);
+        code.call(self.flush);
+        let write_err = |code: &mut Code, msg: Option<(Label, u32)>| {
+            code.mov_imm32(RDI, 2);
+            if let Some((msg, len)) = msg {
+                code.mov_label(RSI, msg);
+                code.mov_imm32(RDX, len);
+            } else {
+                // pop %rdx, pop %rsi
+                code.emit(&[0x5a, 0x5e]);
+            }
+            code.call(write_all);
+        };
+        write_err(code, Some((error_msg, 7)));
+        write_err(code, None);
+        write_err(code, Some((at_msg, 4)));
+        write_err(code, None);
+        write_err(code, Some((newline_msg, 1)));
+        code.mov_imm32(RAX, 60);
+        code.mov_imm32(RDI, 3);
+        code.emit(&SYSCALL);
+
+        let mut messages = vec![
+            (error_msg, "error: "),
+            (at_msg, " at "),
+            (newline_msg, "\n"),
+        ];
+        for (entry, message) in [
+            (self.fail_underflow, "tape underflow"),
+            (self.fail_end, "end of tape"),
+            (self.fail_overflow, "cell overflow"),
+            (self.fail_eof, "unexpected end of input"),
+        ] {
+            let msg = code.label();
+            code.bind(entry);
+            code.mov_label(RSI, msg);
+            code.mov_imm32(RDX, message.len() as u32);
+            code.jmp(fail);
+            messages.push((msg, message));
+        }
+        for (msg, message) in messages {
+            code.bind(msg);
+            code.emit(message.as_bytes());
+        }
+    }
+}
+
+/// Emits the code for one op at a time, mirroring the assembly backend.
+struct Emitter<'a> {
+    config: &'a Config,
+    code: Code,
+    runtime: Runtime,
+    /// Bytes per cell.
+    size: usize,
+    max: u64,
+    tape_end: u64,
+    /// The failure stubs, made on first use, which load an op's location.
+    stubs: HashMap<(usize, Failure), Label>,
+    /// The location string of every op.
+    locs: Vec<(Label, String)>,
+    /// The index of the op being emitted.
+    idx: usize,
+}
+
+/// The kinds of failure an op can jump to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Failure {
+    Underflow,
+    End,
+    Overflow,
+    Eof,
+}
+
+impl Emitter<'_> {
+    fn fail(&mut self, kind: Failure) -> Label {
+        if let Some(label) = self.stubs.get(&(self.idx, kind)) {
+            return *label;
+        }
+        let label = self.code.label();
+        self.stubs.insert((self.idx, kind), label);
+        label
+    }
+
+    /// Fail unless `%rdi` points into the tape.
+    fn check_rdi(&mut self) {
+        let underflow = self.fail(Failure::Underflow);
+        let end = self.fail(Failure::End);
+        self.code.mov_imm(RAX, TAPE);
+        self.code.rr(0x39, RDI, RAX);
+        self.code.jcc(B, underflow);
+        self.code.mov_imm(RAX, self.tape_end);
+        self.code.rr(0x39, RDI, RAX);
+        self.code.jcc(AE, end);
+    }
+
+    fn disp(&self, cells: isize) -> i32 {
+        // far offsets only ever fail the check
+        (cells * self.size as isize).clamp(i32::MIN as isize, i32::MAX as isize) as i32
+    }
+
+    /// The register pointing at `cell[ptr + offset]`, checked against the
+    /// ends of the tape unless it's the current cell.
+    fn cell(&mut self, offset: isize) -> Reg {
+        if offset == 0 {
+            return RBX;
+        }
+        self.code.lea(RDI, RBX, self.disp(offset));
+        self.check_rdi();
+        RDI
+    }
+
+    /// Like `cell` without checking again.
+    fn address(&mut self, offset: isize) -> Reg {
+        if offset == 0 {
+            return RBX;
+        }
+        self.code.lea(RDI, RBX, self.disp(offset));
+        RDI
+    }
+
+    fn shift(&mut self, delta: isize) {
+        self.code.lea(RDI, RBX, self.disp(delta));
+        self.check_rdi();
+        self.code.rr(0x89, RBX, RDI);
+    }
+
+    /// Add `%rax` (with the high half of a product in `%rdx` if `wide`) to
+    /// the cell at `(%base)`, or take it away if `negative`, under the
+    /// overflow policy.
+    fn checked_add(&mut self, base: Reg, negative: bool, wide: bool) {
+        let done = self.code.label();
+        let out = match self.config.overflow {
+            Overflow::Trap => self.fail(Failure::Overflow),
+            _ => self.code.label(),
+        };
+        if wide {
+            self.code.rr(0x85, RDX, RDX);
+            self.code.jcc(NE, out);
+        }
+        self.code.load(self.size, RCX, base);
+        if negative {
+            self.code.rr(0x29, RCX, RAX);
+            self.code.jcc(B, out);
+        } else {
+            self.code.rr(0x01, RCX, RAX);
+            self.code.jcc(B, out);
+            if self.size < 8 {
+                self.code.mov_imm(RAX, self.max);
+                self.code.rr(0x39, RCX, RAX);
+                self.code.jcc(A, out);
+            }
+        }
+        self.code.store(self.size, RCX, base);
+        if self.config.overflow == Overflow::Saturate {
+            self.code.jmp(done);
+            self.code.bind(out);
+            self.code.mov_imm(RCX, if negative { 0 } else { self.max });
+            self.code.store(self.size, RCX, base);
+        }
+        self.code.bind(done);
+    }
+
+    fn update(&mut self, offset: isize, delta: isize) {
+        let base = self.cell(offset);
+        if self.config.overflow == Overflow::Wrap {
+            let delta = (delta as i128).rem_euclid(self.max as i128 + 1) as u64;
+            self.code.mov_imm(RAX, delta);
+            self.code.mem(self.size, 0x00, RAX, base);
+            return;
+        }
+        self.code.mov_imm(RAX, delta.unsigned_abs() as u64);
+        self.checked_add(base, delta < 0, false);
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize) {
+        let skip = self.code.label();
+        self.code.load(self.size, RCX, RBX);
+        self.code.rr(0x85, RCX, RCX);
+        self.code.jcc(E, skip);
+        let base = self.cell(offset);
+        self.code.rr(0x89, RAX, RCX);
+        if self.config.overflow == Overflow::Wrap {
+            self.code.mov_imm(RCX, factor as u64);
+            // imul %rcx, %rax
+            self.code.emit(&[0x48, 0x0f, 0xaf, 0xc1]);
+            self.code.mem(self.size, 0x00, RAX, base);
+        } else {
+            self.code.mov_imm(RCX, factor.unsigned_abs() as u64);
+            // mul %rcx
+            self.code.emit(&[0x48, 0xf7, 0xe1]);
+            self.checked_add(base, factor < 0, true);
+        }
+        self.code.bind(skip);
+    }
+
+    fn scan(&mut self, stride: isize) {
+        let shift = self.size.trailing_zeros() as u8;
+        let size = self.size as i32;
+        match stride {
+            // `repne scas` compares from `%rdi` on, for at most `%rcx`
+            // cells, and stops one cell past the zero it finds
+            1 => {
+                let end = self.fail(Failure::End);
+                self.code.rr(0x89, RDI, RBX);
+                self.code.mov_imm(RCX, self.tape_end);
+                self.code.rr(0x29, RCX, RDI);
+                // shr $shift, %rcx
+                self.code.emit(&[0x48, 0xc1, 0xe9, shift]);
+                self.code.mov_imm32(RAX, 0);
+                self.code.repne_scas(self.size);
+                self.code.jcc(NE, end);
+                self.code.lea(RBX, RDI, -size);
+            }
+            -1 => {
+                let underflow = self.fail(Failure::Underflow);
+                self.code.rr(0x89, RDI, RBX);
+                self.code.rr(0x89, RAX, RBX);
+                self.code.mov_imm(RCX, TAPE);
+                self.code.rr(0x29, RAX, RCX);
+                // shr $shift, %rax
+                self.code.emit(&[0x48, 0xc1, 0xe8, shift]);
+                self.code.lea(RCX, RAX, 1);
+                self.code.mov_imm32(RAX, 0);
+                // std
+                self.code.emit(&[0xfd]);
+                self.code.repne_scas(self.size);
+                // cld
+                self.code.emit(&[0xfc]);
+                self.code.jcc(NE, underflow);
+                self.code.lea(RBX, RDI, size);
+            }
+            _ => {
+                let head = self.code.label();
+                let done = self.code.label();
+                self.code.bind(head);
+                self.code.cmp_zero(self.size, RBX);
+                self.code.jcc(E, done);
+                self.shift(stride);
+                self.code.jmp(head);
+                self.code.bind(done);
+            }
+        }
+    }
+
+    fn input(&mut self, offset: isize) {
+        let eof = self.code.label();
+        let done = self.code.label();
+        // check the cell before reading, then find it again since
+        // `getbyte` changes `%rdi`
+        self.cell(offset);
+        self.code.call(self.runtime.getbyte);
+        // cmp $-1, %eax
+        self.code.emit(&[0x83, 0xf8, 0xff]);
+        self.code.jcc(E, eof);
+        let base = self.address(offset);
+        self.code.store(self.size, RAX, base);
+        self.code.jmp(done);
+        self.code.bind(eof);
+        match self.config.eof {
+            Eof::Unchanged => (),
+            Eof::Zero | Eof::MinusOne => {
+                let value = if self.config.eof == Eof::Zero {
+                    0
+                } else {
+                    u64::MAX
+                };
+                let base = self.address(offset);
+                self.code.mov_imm(RAX, value);
+                self.code.store(self.size, RAX, base);
+            }
+            Eof::Error => {
+                let fail = self.fail(Failure::Eof);
+                self.code.jmp(fail);
+            }
+        }
+        self.code.bind(done);
+    }
+
+    fn output(&mut self, offset: isize, count: isize) {
+        let base = self.cell(offset);
+        // movzbl (%base), %edi, the low byte coming first
+        self.code.emit(&[0x0f, 0xb6, RDI << 3 | base]);
+        self.code.mov_imm(RSI, count as u64);
+        self.code.call(self.runtime.putbytes);
+    }
+
+    fn set(&mut self, offset: isize, value: isize) {
+        let base = self.cell(offset);
+        self.code.mov_imm(RAX, value as u64);
+        self.code.store(self.size, RAX, base);
+    }
+
+    /// Returns the constants to place after the code.
+    fn preset(
+        &mut self,
+        output: &[u8],
+        runs: &[Run],
+        ptr: usize,
+        last: bool,
+    ) -> io::Result<Vec<(Label, Vec<u8>)>> {
+        let mut data = Vec::new();
+        if !output.is_empty() {
+            let label = self.code.label();
+            self.code.mov_label(RSI, label);
+            self.code.mov_imm(RDX, output.len() as u64);
+            self.code.call(self.runtime.putbuf);
+            data.push((label, output.to_vec()));
+        }
+        // nobody can look at the tape once the program ends
+        if last {
+            return Ok(data);
+        }
+        if runs.last().map_or(0, Run::end) > TAPE_CELLS || ptr >= TAPE_CELLS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the program needs more than {} cells", TAPE_CELLS),
+            ));
+        }
+        for run in runs {
+            let label = self.code.label();
+            let bytes: Vec<u8> = run
+                .cells
+                .iter()
+                .flat_map(|cell| cell.to_le_bytes()[..self.size].to_vec())
+                .collect();
+            self.code
+                .mov_imm(RDI, TAPE + (run.start * self.size) as u64);
+            self.code.mov_label(RSI, label);
+            self.code.mov_imm(RCX, bytes.len() as u64);
+            // rep movsb
+            self.code.emit(&[0xf3, 0xa4]);
+            data.push((label, bytes));
+        }
+        self.code.mov_imm(RBX, TAPE + (ptr * self.size) as u64);
+        Ok(data)
+    }
+}
+
+/// The ELF and program headers for `code` loaded at `BASE`, followed by
+/// a segment of `bss_size` zeroed bytes at `BSS`.
+fn headers(code_size: u64, bss_size: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x7fELF");
+    // 64-bit, little endian, version 1, System V
+    out.extend_from_slice(&[2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    // an executable for x86-64
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&0x3eu16.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    // entry, program headers, no section headers, flags
+    out.extend_from_slice(&(BASE + HEADERS).to_le_bytes());
+    out.extend_from_slice(&64u64.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    // header sizes and counts
+    for half in [64u16, 56, 2, 64, 0, 0] {
+        out.extend_from_slice(&half.to_le_bytes());
+    }
+    // (flags, address, size in the file, size in memory)
+    for (flags, address, file_size, mem_size) in [
+        (4 | 1, BASE, HEADERS + code_size, HEADERS + code_size),
+        (4 | 2, BSS, 0, bss_size),
+    ] {
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&(flags as u32).to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&address.to_le_bytes());
+        out.extend_from_slice(&address.to_le_bytes());
+        out.extend_from_slice(&file_size.to_le_bytes());
+        out.extend_from_slice(&mem_size.to_le_bytes());
+        out.extend_from_slice(&0x1000u64.to_le_bytes());
+    }
+    out
+}
+
+impl BfStr {
+    fn _elf(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let (ops, report) = self.optimize_report(pipeline, config);
+        let size = config.cell_bits.bits() as usize / 8;
+        let mut code = Code::default();
+        let runtime = Runtime::new(&mut code);
+        let mut emitter = Emitter {
+            config,
+            code,
+            runtime,
+            size,
+            max: u64::MAX >> (64 - size * 8),
+            tape_end: TAPE + (TAPE_CELLS * size) as u64,
+            stubs: HashMap::new(),
+            locs: Vec::new(),
+            idx: 0,
+        };
+        let bodies: Vec<Label> = ops.iter().map(|_| emitter.code.label()).collect();
+        let ends: Vec<Label> = ops.iter().map(|_| emitter.code.label()).collect();
+        let mut data = Vec::new();
+
+        emitter.code.mov_imm(RBX, TAPE);
+        for (idx, op) in ops.iter().enumerate() {
+            emitter.idx = idx;
+            let loc = emitter.code.label();
+            emitter.locs.push((
+                loc,
+                format!("line {}, column {}", op.span.line, op.span.column),
+            ));
+            match op.operator {
+                Op::Upd => emitter.update(op.offset, op.operand),
+                Op::Shf => emitter.shift(op.operand),
+                Op::Scan { stride } => emitter.scan(stride),
+                Op::Acp => emitter.input(op.offset),
+                Op::Out => emitter.output(op.offset, op.operand),
+                Op::Jpf => {
+                    emitter.code.cmp_zero(size, RBX);
+                    emitter.code.jcc(E, ends[idx]);
+                    emitter.code.bind(bodies[idx]);
+                }
+                Op::Jpb => {
+                    let jpf = op.operand as usize - 1;
+                    emitter.code.cmp_zero(size, RBX);
+                    emitter.code.jcc(NE, bodies[jpf]);
+                    emitter.code.bind(ends[jpf]);
+                }
+                Op::Set => emitter.set(op.offset, op.operand),
+                Op::MulAdd { offset, factor } => emitter.mul_add(offset, factor),
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => data.extend(emitter.preset(output, runs, ptr, idx + 1 == ops.len())?),
+            }
+        }
+        let runtime = &emitter.runtime;
+        let code = &mut emitter.code;
+        code.call(runtime.flush);
+        code.mov_imm32(RAX, 60);
+        code.mov_imm32(RDI, 0);
+        code.emit(&[0x0f, 0x05]);
+
+        let mut stubs: Vec<_> = emitter.stubs.iter().collect();
+        stubs.sort_by_key(|(_, label)| label.0);
+        for ((idx, kind), label) in stubs {
+            let (loc, text) = &emitter.locs[*idx];
+            code.bind(*label);
+            code.mov_label(R8, *loc);
+            code.mov_imm(R9, text.len() as u64);
+            code.jmp(match kind {
+                Failure::Underflow => runtime.fail_underflow,
+                Failure::End => runtime.fail_end,
+                Failure::Overflow => runtime.fail_overflow,
+                Failure::Eof => runtime.fail_eof,
+            });
+        }
+        runtime.emit(code);
+        for (label, text) in &emitter.locs {
+            code.bind(*label);
+            code.emit(text.as_bytes());
+        }
+        for (label, bytes) in data {
+            code.bind(label);
+            code.emit(&bytes);
+        }
+
+        let code = emitter.code.link(BASE + HEADERS);
+        let bss_size = emitter.tape_end - BSS;
+        write.write_all(&headers(code.len() as u64, bss_size))?;
+        write.write_all(&code)?;
+        Ok(report)
+    }
+
+    pub fn elf(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let file = File::create(save_path)?;
+        let report = self._elf(&file, pipeline, config)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(report)
+    }
+}
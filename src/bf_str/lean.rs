@@ -0,0 +1,280 @@
+//! The lean C backend: a fixed `static` tape and a bare cell pointer, with
+//! every op written inline as `*p += 3;` or `p += 2;`, like a hand-written
+//! translator would. Helpers only show up for what needs them: bounds
+//! checks, overflow policies other than wrap, and `--eof error`.
+
+use super::{
+    c_bytes, c_loc, BfStr, CcOptions, Config, Eof, Op, OptReport, Overflow, Pipeline, Run,
+};
+use std::io::{self, Write};
+
+/// Reports a runtime error the same way as the other backends.
+static FAIL: &str = concat!(
+    "static void fail(const char *what, const char *loc) {\n",
+    "    fflush(stdout);\n",
+    "    fprintf(stderr, \"error: %s at %s (tape pointer %td)\\n\", what, loc, p - tape);\n",
+    "    exit(3);\n",
+    "}\n",
+    "\n",
+);
+
+static CHECK: &str = concat!(
+    "#define CHECK(offset, loc)                                            \\\n",
+    "    do {                                                              \\\n",
+    "        ptrdiff_t at = p - tape + (offset);                           \\\n",
+    "        if (at < 0) fail(\"tape underflow\", loc);                      \\\n",
+    "        if (at >= TAPE_CELLS) fail(\"end of tape\", loc);               \\\n",
+    "    } while (0)\n",
+    "\n",
+);
+
+/// Checked cell arithmetic, with `cell_t` replaced by the cell type and
+/// `ON_OVERFLOW` by what the overflow policy does.
+static OVERFLOW: &str = concat!(
+    "static inline cell_t cell_add(cell_t value, int64_t delta, const char *loc) {\n",
+    "    if (delta < 0 ? value < -(uint64_t)delta : (uint64_t)(cell_t)(-1 - value) < (uint64_t)delta) {\n",
+    "        ON_OVERFLOW\n",
+    "    }\n",
+    "    return value + delta;\n",
+    "}\n",
+    "\n",
+    "static inline cell_t cell_mul_add(cell_t value, cell_t src, int64_t factor, const char *loc) {\n",
+    "    uint64_t magnitude = factor < 0 ? -(uint64_t)factor : (uint64_t)factor;\n",
+    "    uint64_t room = factor < 0 ? value : (uint64_t)(cell_t)(-1 - value);\n",
+    "    if (magnitude != 0 && src > room / magnitude) {\n",
+    "        return factor < 0 ? cell_add(0, -1, loc) : cell_add((cell_t)-1, 1, loc);\n",
+    "    }\n",
+    "    return factor < 0 ? value - (uint64_t)src * magnitude : value + (uint64_t)src * magnitude;\n",
+    "}\n",
+    "\n",
+);
+
+/// The cell `offset` away from the pointer.
+fn cell_at(offset: isize) -> String {
+    match offset {
+        0 => String::from("*p"),
+        _ => format!("p[{}]", offset),
+    }
+}
+
+/// `+` or `-`, for writing `delta` as `lhs += n` or `lhs -= n`.
+fn sign(delta: isize) -> char {
+    if delta < 0 {
+        '-'
+    } else {
+        '+'
+    }
+}
+
+/// `lhs += delta;` or `lhs -= -delta;`.
+fn add_assign(lhs: &str, delta: isize) -> String {
+    format!("{} {}= {};", lhs, sign(delta), delta.unsigned_abs())
+}
+
+impl BfStr {
+    pub(super) fn _cc_lean(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+        options: &CcOptions,
+    ) -> io::Result<OptReport> {
+        let (ops, report) = self.optimize_report(pipeline, config);
+        let cell = config.cell_bits.c_type();
+        let checked = options.bounds_check;
+        let wrap = config.overflow == Overflow::Wrap;
+        // what the tape starts out as, if something was preset
+        let mut init = String::new();
+        let mut start = 0;
+        let mut body = Vec::new();
+        let mut depth = 1;
+        for (idx, op) in ops.iter().enumerate() {
+            let loc = c_loc(op);
+            // an access away from the pointer, checked first if asked to
+            let check = |offset: isize| match offset {
+                0 => None,
+                _ if checked => Some(format!("CHECK({}, {});", offset, loc)),
+                _ => None,
+            };
+            let mut stmts: Vec<String> = Vec::new();
+            match op.operator {
+                Op::Upd => {
+                    stmts.extend(check(op.offset));
+                    let target = cell_at(op.offset);
+                    stmts.push(if wrap {
+                        add_assign(&target, op.operand)
+                    } else {
+                        format!("{0} = cell_add({0}, {1}, {2});", target, op.operand, loc)
+                    });
+                }
+                Op::Set => {
+                    stmts.extend(check(op.offset));
+                    stmts.push(format!("{} = {};", cell_at(op.offset), op.operand));
+                }
+                Op::Shf => {
+                    stmts.extend(check(op.operand));
+                    stmts.push(add_assign("p", op.operand));
+                }
+                Op::Scan { stride } => {
+                    let step = add_assign("p", stride);
+                    stmts.push(match check(stride) {
+                        Some(check) => format!("while (*p) {{ {} {} }}", check, step),
+                        None => format!("while (*p) {}", step),
+                    });
+                }
+                Op::Acp => {
+                    stmts.extend(check(op.offset));
+                    let target = cell_at(op.offset);
+                    let store = match config.eof {
+                        Eof::Unchanged => format!("if (c != EOF) {} = c;", target),
+                        Eof::Zero => format!("{} = c == EOF ? 0 : c;", target),
+                        Eof::MinusOne => format!("{} = c == EOF ? ({})-1 : c;", target, cell),
+                        Eof::Error => format!(
+                            "if (c == EOF) fail(\"unexpected end of input\", {}); {} = c;",
+                            loc, target
+                        ),
+                    };
+                    stmts.push(format!("{{ int c = getchar(); {} }}", store));
+                }
+                Op::Out => {
+                    stmts.extend(check(op.offset));
+                    let put = format!("putchar({});", cell_at(op.offset));
+                    stmts.push(match op.operand {
+                        1 => put,
+                        count => format!("for (int i = 0; i < {}; ++i) {}", count, put),
+                    });
+                }
+                Op::Jpf => {
+                    stmts.push(String::from("while (*p) {"));
+                }
+                Op::Jpb => {
+                    depth -= 1;
+                    stmts.push(String::from("}"));
+                }
+                Op::MulAdd { offset, factor } => {
+                    let target = cell_at(offset);
+                    // the source being zero means the loop never ran, so
+                    // the target may well be off the tape
+                    let update = if wrap {
+                        // unsigned, so narrow cells don't overflow an `int`
+                        format!(
+                            "{} {}= *p * {}u;",
+                            target,
+                            sign(factor),
+                            factor.unsigned_abs()
+                        )
+                    } else {
+                        format!(
+                            "{0} = cell_mul_add({0}, *p, {1}, {2});",
+                            target, factor, loc
+                        )
+                    };
+                    stmts.push(match check(offset) {
+                        Some(check) => format!("if (*p) {{ {} {} }}", check, update),
+                        None => format!("if (*p) {}", update),
+                    });
+                }
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => {
+                    if !output.is_empty() {
+                        stmts.push(format!(
+                            "fwrite({}, 1, {}, stdout);",
+                            c_bytes(output),
+                            output.len()
+                        ));
+                    }
+                    // nobody can look at the tape once the program ends
+                    if idx + 1 < ops.len() {
+                        let end = runs.last().map_or(0, Run::end);
+                        if end > options.tape_cells || ptr >= options.tape_cells {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("the program needs more than {} cells", options.tape_cells),
+                            ));
+                        }
+                        if !runs.is_empty() {
+                            // designated, so the zeros between runs are left out
+                            let values: Vec<String> = runs
+                                .iter()
+                                .map(|run| {
+                                    let cells: Vec<String> =
+                                        run.cells.iter().map(|cell| format!("{}u", cell)).collect();
+                                    format!("[{}] = {}", run.start, cells.join(", "))
+                                })
+                                .collect();
+                            init = format!(" = {{{}}}", values.join(", "));
+                        }
+                        start = ptr;
+                    }
+                }
+            }
+            let indent = "    ".repeat(depth);
+            for stmt in stmts {
+                writeln!(body, "{}{}", indent, stmt)?;
+            }
+            if op.operator == Op::Jpf {
+                depth += 1;
+            }
+        }
+
+        // a program worked out entirely at compile time only writes output
+        let tape = ops
+            .iter()
+            .any(|op| !matches!(op.operator, Op::Preset { .. }));
+        // only what the ops go on to use, so nothing is left unused
+        let checked = checked
+            && ops.iter().any(|op| match op.operator {
+                Op::Upd | Op::Set | Op::Acp | Op::Out => op.offset != 0,
+                Op::MulAdd { offset, .. } => offset != 0,
+                Op::Shf | Op::Scan { .. } => true,
+                Op::Jpf | Op::Jpb | Op::Preset { .. } => false,
+            });
+        let arithmetic = ops
+            .iter()
+            .any(|op| matches!(op.operator, Op::Upd | Op::MulAdd { .. }));
+        let input = ops.iter().any(|op| op.operator == Op::Acp);
+        let fails = checked
+            || (arithmetic && config.overflow == Overflow::Trap)
+            || (input && config.eof == Eof::Error);
+        if checked {
+            writeln!(write, "#include <stddef.h>")?;
+        }
+        writeln!(write, "#include <stdint.h>")?;
+        writeln!(write, "#include <stdio.h>")?;
+        if fails {
+            writeln!(write, "#include <stdlib.h>")?;
+        }
+        writeln!(write)?;
+        if tape {
+            writeln!(write, "#define TAPE_CELLS {}\n", options.tape_cells)?;
+            writeln!(write, "static {} tape[TAPE_CELLS]{};", cell, init)?;
+            match start {
+                0 => writeln!(write, "static {} *p = tape;\n", cell)?,
+                _ => writeln!(write, "static {} *p = tape + {};\n", cell, start)?,
+            }
+        }
+        if fails {
+            write!(write, "{}", FAIL)?;
+        }
+        if checked {
+            write!(write, "{}", CHECK)?;
+        }
+        if arithmetic && !wrap {
+            let on_overflow = match config.overflow {
+                Overflow::Trap => "fail(\"cell overflow\", loc);",
+                // saturating never reports where it happened
+                _ => "(void)loc;\n        return delta < 0 ? 0 : (cell_t)-1;",
+            };
+            let helpers = OVERFLOW.replace("ON_OVERFLOW", on_overflow);
+            write!(write, "{}", helpers.replace("cell_t", &cell))?;
+        }
+        writeln!(write, "int main(void) {{")?;
+        write.write_all(&body)?;
+        writeln!(write, "    return 0;")?;
+        writeln!(write, "}}")?;
+        Ok(report)
+    }
+}
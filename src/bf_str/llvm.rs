@@ -0,0 +1,460 @@
+//! The LLVM IR backend: a textual `.ll` module calling into libc for I/O and
+//! memory, which `lli` can run or `llc`/`clang` can build.
+
+use super::{BfStr, Config, Eof, Op, OptReport, Overflow, Pipeline};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Globals, libc declarations and the error messages every module needs.
+static MODULE_HEAD: &str = concat!(
+    "@tape = internal global ptr null\n",
+    "@len = internal global i64 0\n",
+    "@ptr = internal global i64 0\n",
+    "\n",
+    "@underflow.fmt = private constant [48 x i8] c\"error: tape underflow at %s (tape pointer %lu)\\0A\\00\"\n",
+    "@overflow.fmt = private constant [47 x i8] c\"error: cell overflow at %s (tape pointer %lu)\\0A\\00\"\n",
+    "@eof.fmt = private constant [57 x i8] c\"error: unexpected end of input at %s (tape pointer %lu)\\0A\\00\"\n",
+    "\n",
+    "declare ptr @calloc(i64, i64)\n",
+    "declare ptr @realloc(ptr, i64)\n",
+    "declare ptr @memset(ptr, i32, i64)\n",
+    "declare ptr @memcpy(ptr, ptr, i64)\n",
+    "declare i32 @getchar()\n",
+    "declare i32 @putchar(i32)\n",
+    "declare i32 @fflush(ptr)\n",
+    "declare i32 @dprintf(i32, ptr, ...)\n",
+    "declare void @exit(i32)\n",
+    "\n",
+);
+
+/// The tape helpers, with `cell_t` standing for the cell type and
+/// `CELL_SIZE` for its width in bytes.
+static TAPE_HEAD: &str = concat!(
+    "define internal void @fail(ptr %fmt, ptr %loc) {\n",
+    "entry:\n",
+    "  %ptr = load i64, ptr @ptr\n",
+    "  call i32 (i32, ptr, ...) @dprintf(i32 2, ptr %fmt, ptr %loc, i64 %ptr)\n",
+    "  call void @exit(i32 3)\n",
+    "  unreachable\n",
+    "}\n",
+    "\n",
+    "define internal ptr @tape_at(i64 %offset, ptr %loc) {\n",
+    "entry:\n",
+    "  %ptr = load i64, ptr @ptr\n",
+    "  %idx = add i64 %ptr, %offset\n",
+    "  %neg = icmp slt i64 %idx, 0\n",
+    "  br i1 %neg, label %underflow, label %check\n",
+    "underflow:\n",
+    "  call void @fail(ptr @underflow.fmt, ptr %loc)\n",
+    "  unreachable\n",
+    "check:\n",
+    "  %len = load i64, ptr @len\n",
+    "  %fits = icmp ult i64 %idx, %len\n",
+    "  br i1 %fits, label %done, label %grow\n",
+    "grow:\n",
+    "  %double = shl i64 %len, 1\n",
+    "  %need = add i64 %idx, 1\n",
+    "  %more = icmp ugt i64 %need, %double\n",
+    "  %new_len = select i1 %more, i64 %need, i64 %double\n",
+    "  %old = load ptr, ptr @tape\n",
+    "  %bytes = mul i64 %new_len, CELL_SIZE\n",
+    "  %new = call ptr @realloc(ptr %old, i64 %bytes)\n",
+    "  %old_bytes = mul i64 %len, CELL_SIZE\n",
+    "  %tail = getelementptr i8, ptr %new, i64 %old_bytes\n",
+    "  %tail_bytes = sub i64 %bytes, %old_bytes\n",
+    "  call ptr @memset(ptr %tail, i32 0, i64 %tail_bytes)\n",
+    "  store ptr %new, ptr @tape\n",
+    "  store i64 %new_len, ptr @len\n",
+    "  br label %done\n",
+    "done:\n",
+    "  %tape = load ptr, ptr @tape\n",
+    "  %cell = getelementptr cell_t, ptr %tape, i64 %idx\n",
+    "  ret ptr %cell\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_shift(i64 %delta, ptr %loc) {\n",
+    "entry:\n",
+    "  call ptr @tape_at(i64 %delta, ptr %loc)\n",
+    "  %ptr = load i64, ptr @ptr\n",
+    "  %new = add i64 %ptr, %delta\n",
+    "  store i64 %new, ptr @ptr\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_update(i64 %offset, i64 %delta, ptr %loc) {\n",
+    "entry:\n",
+    "  %cell = call ptr @tape_at(i64 %offset, ptr %loc)\n",
+    "  %value = load cell_t, ptr %cell\n",
+    "  %wide = sext i64 %delta to i128\n",
+    "  %new = call cell_t @cell_add(cell_t %value, i128 %wide, ptr %loc)\n",
+    "  store cell_t %new, ptr %cell\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_set(i64 %offset, cell_t %value, ptr %loc) {\n",
+    "entry:\n",
+    "  %cell = call ptr @tape_at(i64 %offset, ptr %loc)\n",
+    "  store cell_t %value, ptr %cell\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal i1 @tape_nonzero() {\n",
+    "entry:\n",
+    "  %cell = call ptr @tape_at(i64 0, ptr null)\n",
+    "  %value = load cell_t, ptr %cell\n",
+    "  %nonzero = icmp ne cell_t %value, 0\n",
+    "  ret i1 %nonzero\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_scan(i64 %stride, ptr %loc) {\n",
+    "entry:\n",
+    "  br label %head\n",
+    "head:\n",
+    "  %nonzero = call i1 @tape_nonzero()\n",
+    "  br i1 %nonzero, label %step, label %done\n",
+    "step:\n",
+    "  call void @tape_shift(i64 %stride, ptr %loc)\n",
+    "  br label %head\n",
+    "done:\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_out(i64 %offset, i64 %step, ptr %loc) {\n",
+    "entry:\n",
+    "  %cell = call ptr @tape_at(i64 %offset, ptr %loc)\n",
+    "  %value = load cell_t, ptr %cell\n",
+    "  %byte = TO_BYTE\n",
+    "  %char = zext i8 %byte to i32\n",
+    "  br label %head\n",
+    "head:\n",
+    "  %i = phi i64 [ 0, %entry ], [ %next, %body ]\n",
+    "  %more = icmp ult i64 %i, %step\n",
+    "  br i1 %more, label %body, label %done\n",
+    "body:\n",
+    "  call i32 @putchar(i32 %char)\n",
+    "  %next = add i64 %i, 1\n",
+    "  br label %head\n",
+    "done:\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal void @write_bytes(ptr %bytes, i64 %len) {\n",
+    "entry:\n",
+    "  br label %head\n",
+    "head:\n",
+    "  %i = phi i64 [ 0, %entry ], [ %next, %body ]\n",
+    "  %more = icmp ult i64 %i, %len\n",
+    "  br i1 %more, label %body, label %done\n",
+    "body:\n",
+    "  %p = getelementptr i8, ptr %bytes, i64 %i\n",
+    "  %byte = load i8, ptr %p\n",
+    "  %char = zext i8 %byte to i32\n",
+    "  call i32 @putchar(i32 %char)\n",
+    "  %next = add i64 %i, 1\n",
+    "  br label %head\n",
+    "done:\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_muladd(i64 %offset, i64 %factor, ptr %loc) {\n",
+    "entry:\n",
+    "  %src_cell = call ptr @tape_at(i64 0, ptr %loc)\n",
+    "  %src = load cell_t, ptr %src_cell\n",
+    "  %zero = icmp eq cell_t %src, 0\n",
+    "  br i1 %zero, label %done, label %add\n",
+    "add:\n",
+    "  %cell = call ptr @tape_at(i64 %offset, ptr %loc)\n",
+    "  %value = load cell_t, ptr %cell\n",
+    "  %wide = zext cell_t %src to i128\n",
+    "  %wide_factor = sext i64 %factor to i128\n",
+    "  %product = mul i128 %wide, %wide_factor\n",
+    "  %new = call cell_t @cell_add(cell_t %value, i128 %product, ptr %loc)\n",
+    "  store cell_t %new, ptr %cell\n",
+    "  br label %done\n",
+    "done:\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+    "define internal void @tape_init() {\n",
+    "entry:\n",
+    "  %tape = call ptr @calloc(i64 1024, i64 CELL_SIZE)\n",
+    "  store ptr %tape, ptr @tape\n",
+    "  store i64 1024, ptr @len\n",
+    "  ret void\n",
+    "}\n",
+    "\n",
+);
+
+/// `@cell_add(value, delta, loc)` under each overflow policy. The sum is
+/// worked out in `i128`, which holds any cell plus any delta exactly.
+fn cell_add(overflow: Overflow, max: i128) -> String {
+    let mut def = String::from(concat!(
+        "define internal cell_t @cell_add(cell_t %value, i128 %delta, ptr %loc) {\n",
+        "entry:\n",
+        "  %wide = zext cell_t %value to i128\n",
+        "  %sum = add i128 %wide, %delta\n",
+    ));
+    match overflow {
+        Overflow::Wrap => def.push_str("  %new = trunc i128 %sum to cell_t\n"),
+        Overflow::Saturate => def.push_str(&format!(
+            concat!(
+                "  %under = icmp slt i128 %sum, 0\n",
+                "  %over = icmp sgt i128 %sum, {max}\n",
+                "  %low = select i1 %under, i128 0, i128 %sum\n",
+                "  %clamped = select i1 %over, i128 {max}, i128 %low\n",
+                "  %new = trunc i128 %clamped to cell_t\n",
+            ),
+            max = max
+        )),
+        Overflow::Trap => def.push_str(&format!(
+            concat!(
+                "  %under = icmp slt i128 %sum, 0\n",
+                "  %over = icmp sgt i128 %sum, {max}\n",
+                "  %bad = or i1 %under, %over\n",
+                "  br i1 %bad, label %trap, label %ok\n",
+                "trap:\n",
+                "  call void @fail(ptr @overflow.fmt, ptr %loc)\n",
+                "  unreachable\n",
+                "ok:\n",
+                "  %new = trunc i128 %sum to cell_t\n",
+            ),
+            max = max
+        )),
+    }
+    def.push_str("  ret cell_t %new\n}\n\n");
+    def
+}
+
+/// `@tape_in(offset, loc)` under each EOF mode.
+fn tape_in(eof: Eof) -> String {
+    let on_eof = match eof {
+        Eof::Unchanged => "",
+        Eof::Zero => "  store cell_t 0, ptr %cell\n",
+        Eof::MinusOne => "  store cell_t -1, ptr %cell\n",
+        Eof::Error => "  call void @fail(ptr @eof.fmt, ptr %loc)\n  unreachable\n",
+    };
+    let mut def = String::from(concat!(
+        "define internal void @tape_in(i64 %offset, ptr %loc) {\n",
+        "entry:\n",
+        "  %cell = call ptr @tape_at(i64 %offset, ptr %loc)\n",
+        "  call i32 @fflush(ptr null)\n",
+        "  %char = call i32 @getchar()\n",
+        "  %at_eof = icmp eq i32 %char, -1\n",
+        "  br i1 %at_eof, label %eof, label %read\n",
+        "read:\n",
+        "  %byte = trunc i32 %char to i8\n",
+        "  %value = FROM_BYTE\n",
+        "  store cell_t %value, ptr %cell\n",
+        "  br label %done\n",
+        "eof:\n",
+    ));
+    def.push_str(on_eof);
+    if eof != Eof::Error {
+        def.push_str("  br label %done\n");
+    }
+    def.push_str("done:\n  ret void\n}\n\n");
+    def
+}
+
+/// A cast between integer types, or a no-op `bitcast` when they're the same.
+fn resize(value: &str, from: u32, to: u32) -> String {
+    let cast = match from.cmp(&to) {
+        std::cmp::Ordering::Less => "zext",
+        std::cmp::Ordering::Equal => "bitcast",
+        std::cmp::Ordering::Greater => "trunc",
+    };
+    format!("{} i{} {} to i{}", cast, from, value, to)
+}
+
+/// `value` as an `iN` constant, i.e. modulo `2^N` and signed.
+fn llvm_int(value: i128, bits: u32) -> i128 {
+    let modulus = 1i128 << bits;
+    let value = value.rem_euclid(modulus);
+    if value >= modulus / 2 {
+        value - modulus
+    } else {
+        value
+    }
+}
+
+/// `bytes` as an LLVM string constant.
+fn llvm_bytes(bytes: &[u8]) -> String {
+    let mut literal = String::from("c\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => literal.push_str(&format!("\\{:02X}", byte)),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\{:02X}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+impl BfStr {
+    fn _llvm(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let (ops, report) = self.optimize_report(pipeline, config);
+        let bits = config.cell_bits.bits();
+        let cell_t = config.cell_bits.llvm_type();
+        // constants for source locations, preset output and preset cells
+        let mut consts: Vec<String> = Vec::new();
+        let mut cmds: Vec<String> = Vec::new();
+        for (idx, op) in ops.iter().enumerate() {
+            let loc = format!("@loc.{}", idx);
+            let loc_text = format!("line {}, column {}", op.span.line, op.span.column);
+            consts.push(format!(
+                "{} = private constant [{} x i8] {}",
+                loc,
+                loc_text.len() + 1,
+                llvm_bytes(format!("{}\0", loc_text).as_bytes())
+            ));
+            match op.operator {
+                Op::Upd => cmds.push(format!(
+                    "  call void @tape_update(i64 {}, i64 {}, ptr {})\n",
+                    op.offset, op.operand, loc
+                )),
+                Op::Shf => cmds.push(format!(
+                    "  call void @tape_shift(i64 {}, ptr {})\n",
+                    op.operand, loc
+                )),
+                Op::Scan { stride } => cmds.push(format!(
+                    "  call void @tape_scan(i64 {}, ptr {})\n",
+                    stride, loc
+                )),
+                Op::Acp => cmds.push(format!(
+                    "  call void @tape_in(i64 {}, ptr {})\n",
+                    op.offset, loc
+                )),
+                Op::Out => cmds.push(format!(
+                    "  call void @tape_out(i64 {}, i64 {}, ptr {})\n",
+                    op.offset, op.operand, loc
+                )),
+                // `[` checks the cell on the way in and `]` jumps back to
+                // the check, which is the same as jumping past `[` when the
+                // cell is non-zero
+                Op::Jpf => cmds.push(format!(
+                    concat!(
+                        "  br label %head{idx}\n",
+                        "head{idx}:\n",
+                        "  %nonzero{idx} = call i1 @tape_nonzero()\n",
+                        "  br i1 %nonzero{idx}, label %body{idx}, label %end{idx}\n",
+                        "body{idx}:\n",
+                    ),
+                    idx = idx
+                )),
+                Op::Jpb => cmds.push(format!(
+                    "  br label %head{jpf}\nend{jpf}:\n",
+                    jpf = op.operand - 1
+                )),
+                Op::Set => cmds.push(format!(
+                    "  call void @tape_set(i64 {}, {} {}, ptr {})\n",
+                    op.offset,
+                    cell_t,
+                    llvm_int(op.operand as i128, bits),
+                    loc
+                )),
+                Op::MulAdd { offset, factor } => cmds.push(format!(
+                    "  call void @tape_muladd(i64 {}, i64 {}, ptr {})\n",
+                    offset, factor, loc
+                )),
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => {
+                    let mut cmd = String::new();
+                    if !output.is_empty() {
+                        consts.push(format!(
+                            "@output.{} = private constant [{} x i8] {}",
+                            idx,
+                            output.len(),
+                            llvm_bytes(output)
+                        ));
+                        cmd.push_str(&format!(
+                            "  call void @write_bytes(ptr @output.{}, i64 {})\n",
+                            idx,
+                            output.len()
+                        ));
+                    }
+                    // nobody can look at the tape once the program ends
+                    if idx + 1 < ops.len() {
+                        for (n, run) in runs.iter().enumerate() {
+                            let values: Vec<String> = run
+                                .cells
+                                .iter()
+                                .map(|&cell| format!("{} {}", cell_t, llvm_int(cell as i128, bits)))
+                                .collect();
+                            consts.push(format!(
+                                "@cells.{}.{} = private constant [{} x {}] [{}]",
+                                idx,
+                                n,
+                                run.cells.len(),
+                                cell_t,
+                                values.join(", ")
+                            ));
+                            // the tape may move while it grows, so it's loaded
+                            // after `tape_at` has made room for the run
+                            cmd.push_str(&format!(
+                                concat!(
+                                    "  call ptr @tape_at(i64 {last}, ptr null)\n",
+                                    "  %tape{idx}.{n} = load ptr, ptr @tape\n",
+                                    "  %run{idx}.{n} = getelementptr {cell_t}, ptr %tape{idx}.{n}, i64 {start}\n",
+                                    "  call ptr @memcpy(ptr %run{idx}.{n}, ptr @cells.{idx}.{n}, i64 {bytes})\n",
+                                ),
+                                last = run.end() - 1,
+                                idx = idx,
+                                n = n,
+                                cell_t = cell_t,
+                                start = run.start,
+                                bytes = run.cells.len() * bits as usize / 8
+                            ));
+                        }
+                        cmd.push_str(&format!("  call void @tape_shift(i64 {}, ptr null)\n", ptr));
+                    }
+                    cmds.push(cmd);
+                }
+            }
+        }
+        let size = (bits / 8).to_string();
+        let max = (1i128 << bits) - 1;
+        let tape_head = TAPE_HEAD
+            .replace("TO_BYTE", &resize("%value", bits, 8))
+            .replace("CELL_SIZE", &size);
+        let tape_in = tape_in(config.eof).replace("FROM_BYTE", &resize("%byte", 8, bits));
+        write!(write, "{}", MODULE_HEAD)?;
+        for line in &consts {
+            writeln!(write, "{}", line)?;
+        }
+        writeln!(write)?;
+        for def in [tape_head, cell_add(config.overflow, max), tape_in] {
+            write!(write, "{}", def.replace("cell_t", &cell_t))?;
+        }
+        write!(
+            write,
+            "define i32 @main() {{\nentry:\n  call void @tape_init()\n"
+        )?;
+        for cmd in &cmds {
+            write!(write, "{}", cmd)?;
+        }
+        write!(write, "  ret i32 0\n}}\n")?;
+        Ok(report)
+    }
+
+    pub fn llvm(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let file = File::create(save_path)?;
+        self._llvm(file, pipeline, config)
+    }
+}
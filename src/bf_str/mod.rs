@@ -1,8 +1,27 @@
+mod asm;
+mod build;
+mod cell;
+mod dump;
+mod elf;
+mod lean;
+mod llvm;
+mod passes;
+mod rust;
+mod wasm;
+
+pub use build::BuildOptions;
+use cell::Cell;
+pub use cell::{CellBits, Eof, Overflow};
+pub use dump::IrFormat;
+pub use passes::{OptReport, Pass, Pipeline};
 use std::{
     collections::HashMap,
+    error::Error,
+    fmt,
     fs::File,
-    io::{self, Read, Write},
+    io::{self, BufWriter, Read, Write},
     path::Path,
+    str::FromStr,
     sync::LazyLock,
 };
 
@@ -15,9 +34,40 @@ enum Op {
     Jpf,
     Jpb,
     Set,
-    // Mov,
-    Mul,
-    Add,
+    /// Add `factor * cell[ptr]` to `cell[ptr + offset]`, leaving `cell[ptr]`
+    /// as it is.
+    MulAdd {
+        offset: isize,
+        factor: isize,
+    },
+    /// Move by `stride` until the current cell is zero, i.e. `[>]`, `[<]`
+    /// or `[>>>]`.
+    Scan {
+        stride: isize,
+    },
+    /// Write `output`, then load each of `runs` into the tape and move to
+    /// cell `ptr`: what the input-free start of the program does, worked out
+    /// at compile time. Only ever the first op. Its operand is how many ops
+    /// that took, which still count towards [`Config::step_limit`].
+    Preset {
+        output: Vec<u8>,
+        runs: Vec<Run>,
+        ptr: usize,
+    },
+}
+
+/// Cells a `Preset` loads from cell `start` on. Cells between runs are 0.
+#[derive(Debug, Clone, PartialEq)]
+struct Run {
+    start: usize,
+    cells: Vec<u64>,
+}
+
+impl Run {
+    /// One past the last cell of the run.
+    fn end(&self) -> usize {
+        self.start + self.cells.len()
+    }
 }
 
 static OP_MAP: LazyLock<HashMap<char, Op>> = LazyLock::new(|| {
@@ -33,10 +83,57 @@ static OP_MAP: LazyLock<HashMap<char, Op>> = LazyLock::new(|| {
     ])
 });
 
+/// Where an operation came from in the source file.
+///
+/// `start..end` is a byte range; `line` and `column` (both 1-based, column
+/// counted in chars) locate `start` for diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`.
+    pub fn merge(self, other: Span) -> Span {
+        let (first, last) = if self.start <= other.start {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Span {
+            end: first.end.max(last.end),
+            ..first
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct Operation {
     operator: Op,
     operand: isize,
+    /// `Upd`, `Set`, `Out` and `Acp` act on `cell[ptr + offset]`.
+    offset: isize,
+    span: Span,
+}
+
+impl Operation {
+    fn new(operator: Op, operand: isize, span: Span) -> Self {
+        Operation {
+            operator,
+            operand,
+            offset: 0,
+            span,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -46,184 +143,516 @@ pub struct BfStr {
 
 impl Default for BfStr {
     fn default() -> Self {
-        return BfStr {
+        BfStr {
             ops: Vec::with_capacity(1024),
-        };
+        }
     }
 }
 
-fn count_step(chars: &[char], to_match: &char) -> (usize, usize) {
-    let mut count = 0;
-    let mut empty = 0;
-    for c in chars {
-        if c == to_match {
-            count += 1;
-        } else if !OP_MAP.contains_key(c) {
-            empty += 1;
-        } else {
+/// Sum the run of chars at the start of `chars` that `step` gives a value,
+/// e.g. `+++--` with `+` as 1 and `-` as -1 sums to 1. Returns the sum and
+/// how many chars the run takes, comments in between included but trailing
+/// comments after the last counted char not.
+fn count_step(chars: &[char], step: impl Fn(char) -> Option<isize>) -> (isize, usize) {
+    let mut sum = 0;
+    let mut len = 0;
+    for (idx, c) in chars.iter().enumerate() {
+        if let Some(value) = step(*c) {
+            sum += value;
+            len = idx + 1;
+        } else if OP_MAP.contains_key(c) {
             break;
         }
     }
-    (count, empty)
+    (sum, len)
+}
+
+/// Which bracket a [`ParseError`] points at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bracket {
+    Open,
+    Close,
+}
+
+/// An unmatched `[` or `]`, located in the source it was parsed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub bracket: Bracket,
+    /// 1-based line of the bracket.
+    pub line: usize,
+    /// 1-based column of the bracket, counted in chars.
+    pub column: usize,
+    source_line: String,
+}
+
+impl ParseError {
+    fn new(bracket: Bracket, chars: &[char], pos: usize) -> Self {
+        let line_start = chars[..pos]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |idx| idx + 1);
+        let line_end = chars[pos..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(chars.len(), |idx| pos + idx);
+        ParseError {
+            bracket,
+            line: chars[..pos].iter().filter(|c| **c == '\n').count() + 1,
+            column: pos - line_start + 1,
+            source_line: chars[line_start..line_end].iter().collect(),
+        }
+    }
 }
 
-impl From<&str> for BfStr {
-    fn from(text: &str) -> Self {
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bracket = match self.bracket {
+            Bracket::Open => '[',
+            Bracket::Close => ']',
+        };
+        writeln!(
+            f,
+            "unmatched `{}` at line {}, column {}",
+            bracket, self.line, self.column
+        )?;
+        // keep tabs so the caret lines up with the snippet
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line.trim_end())?;
+        write!(f, "{} | {}^", gutter, indent)
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl BfStr {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
         let mut bf_str = BfStr::default();
         let chars: Vec<char> = text.chars().collect();
+        let spans = char_spans(text);
+        let span_of = |from: usize, len: usize| Span {
+            end: spans[from + len - 1].end,
+            ..spans[from]
+        };
         let mut pos: usize = 0;
 
-        let mut jmp_stack: Vec<usize> = Vec::new();
+        // (op index, char position) of every `[` still waiting for its `]`
+        let mut jmp_stack: Vec<(usize, usize)> = Vec::new();
         while pos < chars.len() {
             let c = chars[pos];
             match c {
-                '+' | ',' | '.' | '>' => {
-                    let (operand, empty) = count_step(&chars[pos..], &c);
-                    let op = Operation {
-                        operator: OP_MAP[&c].clone(),
-                        operand: operand as isize,
-                    };
-                    pos += operand;
-                    pos += empty;
-                    bf_str.ops.push(op);
+                '+' | '-' | '>' | '<' | '.' => {
+                    // opposite chars cancel out, and a run summing to zero
+                    // is no op at all
+                    let (operand, len) = count_step(&chars[pos..], |next| match (c, next) {
+                        ('+' | '-', '+') | ('>' | '<', '>') | ('.', '.') => Some(1),
+                        ('+' | '-', '-') | ('>' | '<', '<') => Some(-1),
+                        _ => None,
+                    });
+                    if operand != 0 {
+                        let op = Operation::new(OP_MAP[&c].clone(), operand, span_of(pos, len));
+                        bf_str.ops.push(op);
+                    }
+                    pos += len;
                 }
-                '-' | '<' => {
-                    let (operand, empty) = count_step(&chars[pos..], &c);
-                    let op = Operation {
-                        operator: OP_MAP[&c].clone(),
-                        operand: -(operand as isize),
-                    };
-                    pos += operand;
-                    pos += empty;
+                ',' => {
+                    // every `,` reads its own byte, so they don't fold
+                    let op = Operation::new(OP_MAP[&c].clone(), 1, span_of(pos, 1));
                     bf_str.ops.push(op);
+                    pos += 1;
                 }
                 '[' => {
-                    let op = Operation {
-                        operator: OP_MAP[&c].clone(),
-                        operand: 0,
-                    };
-                    pos += 1;
+                    let op = Operation::new(OP_MAP[&c].clone(), 0, span_of(pos, 1));
                     bf_str.ops.push(op);
-                    jmp_stack.push(bf_str.ops.len() - 1);
+                    jmp_stack.push((bf_str.ops.len() - 1, pos));
+                    pos += 1;
                 }
                 ']' => match jmp_stack.pop() {
-                    Some(idx) => {
-                        let op = Operation {
-                            operator: OP_MAP[&c].clone(),
-                            operand: (idx + 1) as isize,
-                        };
+                    Some((idx, _)) => {
+                        let op =
+                            Operation::new(OP_MAP[&c].clone(), (idx + 1) as isize, span_of(pos, 1));
                         pos += 1;
                         bf_str.ops.push(op);
                         bf_str.ops[idx].operand = bf_str.ops.len() as isize;
                     }
-                    None => panic!("Unbalance jump!"),
+                    None => return Err(ParseError::new(Bracket::Close, &chars, pos)),
                 },
                 _ => pos += 1,
             }
         }
-        bf_str
+        if let Some((_, open)) = jmp_stack.pop() {
+            return Err(ParseError::new(Bracket::Open, &chars, open));
+        }
+        Ok(bf_str)
     }
 }
 
+/// The span of every char in `text`, indexed by char position.
+fn char_spans(text: &str) -> Vec<Span> {
+    let mut line = 1;
+    let mut column = 1;
+    text.char_indices()
+        .map(|(start, c)| {
+            let span = Span {
+                start,
+                end: start + c.len_utf8(),
+                line,
+                column,
+            };
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+            span
+        })
+        .collect()
+}
+
 impl BfStr {
     pub fn from_file(path: &Path) -> io::Result<Self> {
         let mut file = File::open(path)?;
         let mut source = String::new();
         file.read_to_string(&mut source)?;
-        Ok(BfStr::from(source.as_str()))
+        Ok(BfStr::parse(&source)?)
     }
 
-    pub fn interpret(&self) {
-        self._interpret(io::stdin(), io::stdout());
+    pub fn interpret(&self, pipeline: &Pipeline, config: &Config) -> Result<(), InterpretError> {
+        self._interpret(pipeline, config, io::stdin(), io::stdout())
     }
 
-    fn _interpret(&self, mut read: impl Read, mut write: impl Write) {
-        let mut tape: Vec<u8> = vec![0; 1024000];
-        let mut prt: usize = 0;
-        let mut pos: usize = 0;
-        while pos < self.ops.len() {
-            let op = &self.ops[pos];
+    fn _interpret(
+        &self,
+        pipeline: &Pipeline,
+        config: &Config,
+        read: impl Read,
+        write: impl Write,
+    ) -> Result<(), InterpretError> {
+        let ops = &self.optimize(pipeline, config);
+        match config.cell_bits {
+            CellBits::U8 => execute::<u8>(ops, config, read, write).map(drop),
+            CellBits::U16 => execute::<u16>(ops, config, read, write).map(drop),
+            CellBits::U32 => execute::<u32>(ops, config, read, write).map(drop),
+            CellBits::U64 => execute::<u64>(ops, config, read, write).map(drop),
+        }
+    }
+}
+
+/// Run `ops` to completion and return the final tape.
+fn execute<C: Cell>(
+    ops: &[Operation],
+    config: &Config,
+    mut read: impl Read,
+    write: impl Write,
+) -> Result<Vec<C>, InterpretError> {
+    // `.` writes raw bytes; flushed before every `,` and at exit so
+    // prompts show up before the program blocks on input
+    let mut write = BufWriter::new(write);
+    let mut machine = Machine::new(1024000);
+    machine.run(ops, ops.len(), config, &mut read, &mut write)?;
+    write.flush().map_err(|source| InterpretError::Io {
+        op: machine.pos,
+        span: ops.last().map(|op| op.span).unwrap_or_default(),
+        ptr: machine.prt,
+        source,
+    })?;
+    Ok(machine.tape)
+}
+
+/// The interpreter state between two ops, so a run can stop at any op and
+/// carry on later.
+#[derive(Debug, Clone)]
+struct Machine<C> {
+    tape: Vec<C>,
+    prt: usize,
+    /// The index of the next op to run.
+    pos: usize,
+    steps: u64,
+}
+
+impl<C: Cell> Machine<C> {
+    fn new(len: usize) -> Self {
+        Machine {
+            tape: vec![C::default(); len.max(1)],
+            prt: 0,
+            pos: 0,
+            steps: 0,
+        }
+    }
+
+    /// Run `ops` until the next op would be `ops[end]`.
+    fn run(
+        &mut self,
+        ops: &[Operation],
+        end: usize,
+        config: &Config,
+        read: &mut impl Read,
+        write: &mut impl Write,
+    ) -> Result<(), InterpretError> {
+        while self.pos < end {
+            let (pos, prt) = (self.pos, self.prt);
+            let op = &ops[pos];
+            let cost = match op.operator {
+                Op::Preset { .. } => op.operand as u64,
+                _ => 1,
+            };
+            if let Some(limit) = config.step_limit {
+                if self.steps + cost > limit {
+                    return Err(InterpretError::StepLimit {
+                        op: pos,
+                        span: op.span,
+                        ptr: prt,
+                        limit,
+                    });
+                }
+            }
+            self.steps += cost;
+            let underflow = || InterpretError::TapeUnderflow {
+                op: pos,
+                span: op.span,
+                ptr: prt,
+            };
+            let overflow = || InterpretError::Overflow {
+                op: pos,
+                span: op.span,
+                ptr: prt,
+            };
+            let io_error = |source| InterpretError::Io {
+                op: pos,
+                span: op.span,
+                ptr: prt,
+                source,
+            };
             match op.operator {
                 Op::Upd => {
-                    tape[prt] = (tape[prt] as isize + op.operand) as u8;
-                    pos += 1;
+                    let idx = shift(&mut self.tape, prt, op.offset).ok_or_else(underflow)?;
+                    self.tape[idx] = self.tape[idx]
+                        .add_with(op.operand as i128, config.overflow)
+                        .ok_or_else(overflow)?;
+                    self.pos += 1;
                 }
                 Op::Shf => {
-                    if op.operand > 0 {
-                        prt += op.operand as usize;
-                        while prt >= tape.len() {
-                            tape.push(0);
-                        }
-                    } else {
-                        let ret = (-op.operand) as usize;
-                        if prt < ret {
-                            panic!("Tape underflow!");
-                        }
-                        prt -= ret;
-                    }
-                    pos += 1;
+                    self.prt = shift(&mut self.tape, prt, op.operand).ok_or_else(underflow)?;
+                    self.pos += 1;
                 }
                 Op::Jpf => {
-                    if tape[prt] == 0 {
-                        pos = op.operand as usize;
+                    if self.tape[prt].is_zero() {
+                        self.pos = op.operand as usize;
                     } else {
-                        pos += 1;
+                        self.pos += 1;
                     }
                 }
                 Op::Jpb => {
-                    if tape[prt] != 0 {
-                        pos = op.operand as usize;
+                    if !self.tape[prt].is_zero() {
+                        self.pos = op.operand as usize;
                     } else {
-                        pos += 1;
+                        self.pos += 1;
                     }
                 }
                 Op::Out => {
-                    for _ in 0..op.operand {
-                        write!(write, "{}", char::from(tape[prt])).unwrap();
-                    }
-                    pos += 1;
+                    let idx = shift(&mut self.tape, prt, op.offset).ok_or_else(underflow)?;
+                    let bytes = vec![self.tape[idx].to_byte(); op.operand as usize];
+                    write.write_all(&bytes).map_err(io_error)?;
+                    self.pos += 1;
                 }
                 Op::Acp => {
+                    let idx = shift(&mut self.tape, prt, op.offset).ok_or_else(underflow)?;
+                    write.flush().map_err(io_error)?;
                     let mut buf = [0; 1];
-                    let _ = read.read_exact(&mut buf);
-                    if buf[0] != 0 {
-                        tape[prt] = buf[0];
+                    match read.read_exact(&mut buf) {
+                        Ok(()) => self.tape[idx] = C::from_byte(buf[0]),
+                        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                            match config.eof {
+                                Eof::Unchanged => (),
+                                Eof::Zero => self.tape[idx] = C::default(),
+                                Eof::MinusOne => self.tape[idx] = C::from_i128(-1),
+                                Eof::Error => {
+                                    return Err(InterpretError::UnexpectedEof {
+                                        op: pos,
+                                        span: op.span,
+                                        ptr: prt,
+                                    })
+                                }
+                            }
+                        }
+                        Err(err) => return Err(io_error(err)),
                     }
-                    pos += 1;
+                    self.pos += 1;
                 }
                 Op::Set => {
-                    tape[prt] = op.operand as u8;
-                    pos += 1;
+                    let idx = shift(&mut self.tape, prt, op.offset).ok_or_else(underflow)?;
+                    self.tape[idx] = C::from_i128(op.operand as i128);
+                    self.pos += 1;
                 }
-                Op::Mul => {
-                    tape[prt] = (op.operand * tape[prt] as isize) as u8;
-                    pos += 1;
+                Op::Scan { stride } => {
+                    self.prt = scan(&mut self.tape, prt, stride).ok_or_else(underflow)?;
+                    self.pos += 1;
                 }
-                // Op::Mov => {
-                //     tape[prt + op.operand] = tape[prt];
-                //     tape[prt] = 0;
-                //     pos += 1;
-                // }
-                Op::Add => {
-                    // add current value to relative operand cell
-                    let new_prt;
-                    if op.operand > 0 {
-                        new_prt = prt + op.operand as usize;
-                        while new_prt >= tape.len() {
-                            tape.push(0);
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => {
+                    write.write_all(output).map_err(io_error)?;
+                    for run in runs {
+                        if self.tape.len() < run.end() {
+                            self.tape.resize(run.end(), C::default());
                         }
-                    } else {
-                        let ret = (-op.operand) as usize;
-                        if prt < ret {
-                            panic!("Tape underflow!");
+                        for (cell, &value) in self.tape[run.start..].iter_mut().zip(&run.cells) {
+                            *cell = C::from_i128(value as i128);
                         }
-                        new_prt = prt - ret;
                     }
-                    tape[new_prt] += tape[prt];
-                    pos += 1;
+                    self.prt = shift(&mut self.tape, 0, ptr as isize).ok_or_else(underflow)?;
+                    self.pos += 1;
+                }
+                Op::MulAdd { offset, factor } => {
+                    // the target is never touched when the loop this came from
+                    // wouldn't have run, so it can't underflow either
+                    if !self.tape[prt].is_zero() {
+                        let new_prt = shift(&mut self.tape, prt, offset).ok_or_else(underflow)?;
+                        self.tape[new_prt] = self.tape[new_prt]
+                            .mul_add_with(self.tape[prt], factor as i128, config.overflow)
+                            .ok_or_else(overflow)?;
+                    }
+                    self.pos += 1;
                 }
             }
         }
+        Ok(())
+    }
+}
+
+/// Move `prt` by `delta`, growing the tape to the right as needed.
+/// Returns `None` if the move would go left of cell 0.
+fn shift<C: Cell>(tape: &mut Vec<C>, prt: usize, delta: isize) -> Option<usize> {
+    let new_prt = prt.checked_add_signed(delta)?;
+    if new_prt >= tape.len() {
+        tape.resize(new_prt + 1, C::default());
+    }
+    Some(new_prt)
+}
+
+/// Where `[>]`-style loops starting at `prt` stop, growing the tape if they
+/// run off the right end. Returns `None` if they run off the left end.
+fn scan<C: Cell>(tape: &mut Vec<C>, prt: usize, stride: isize) -> Option<usize> {
+    match stride {
+        1 => match tape[prt..].iter().position(|cell| cell.is_zero()) {
+            Some(idx) => Some(prt + idx),
+            None => shift(tape, prt, (tape.len() - prt) as isize),
+        },
+        -1 => tape[..=prt].iter().rposition(|cell| cell.is_zero()),
+        _ => {
+            let mut prt = prt;
+            while !tape[prt].is_zero() {
+                prt = shift(tape, prt, stride)?;
+            }
+            Some(prt)
+        }
+    }
+}
+
+/// Interpreter settings.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Width of every tape cell, in both the interpreter and generated C.
+    pub cell_bits: CellBits,
+    /// What `+`, `-` and multiply loops do when a cell leaves its range.
+    pub overflow: Overflow,
+    /// What `,` stores when input is exhausted.
+    pub eof: Eof,
+    /// Give up with [`InterpretError::StepLimit`] after executing this many ops.
+    pub step_limit: Option<u64>,
+}
+
+/// Why the interpreter stopped before reaching the end of the program.
+///
+/// Every variant records the index of the op being executed, where that op
+/// came from in the source, and the tape pointer at that moment.
+#[derive(Debug)]
+pub enum InterpretError {
+    /// The op would move the tape pointer left of cell 0.
+    TapeUnderflow { op: usize, span: Span, ptr: usize },
+    /// A cell left its range under [`Overflow::Trap`].
+    Overflow { op: usize, span: Span, ptr: usize },
+    /// `,` found no more input under [`Eof::Error`].
+    UnexpectedEof { op: usize, span: Span, ptr: usize },
+    /// Reading input or writing output failed.
+    Io {
+        op: usize,
+        span: Span,
+        ptr: usize,
+        source: io::Error,
+    },
+    /// [`Config::step_limit`] ops were executed without the program finishing.
+    StepLimit {
+        op: usize,
+        span: Span,
+        ptr: usize,
+        limit: u64,
+    },
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (op, span, ptr) = match self {
+            InterpretError::TapeUnderflow { op, span, ptr } => {
+                write!(f, "tape underflow")?;
+                (op, span, ptr)
+            }
+            InterpretError::Overflow { op, span, ptr } => {
+                write!(f, "cell overflow")?;
+                (op, span, ptr)
+            }
+            InterpretError::UnexpectedEof { op, span, ptr } => {
+                write!(f, "unexpected end of input")?;
+                (op, span, ptr)
+            }
+            InterpretError::Io {
+                op,
+                span,
+                ptr,
+                source,
+            } => {
+                write!(f, "I/O error: {}", source)?;
+                (op, span, ptr)
+            }
+            InterpretError::StepLimit {
+                op,
+                span,
+                ptr,
+                limit,
+            } => {
+                write!(f, "step limit of {} exhausted", limit)?;
+                (op, span, ptr)
+            }
+        };
+        write!(
+            f,
+            " at line {}, column {} (op #{}, tape pointer {})",
+            span.line, span.column, op, ptr
+        )
+    }
+}
+
+impl Error for InterpretError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InterpretError::Io { source, .. } => Some(source),
+            _ => None,
+        }
     }
 }
 
@@ -234,6 +663,7 @@ static FILE_HEAD: &str = concat!(
     "#include <stdint.h>\n",
     "#include <stdio.h>\n",
     "#include <stdlib.h>\n",
+    "#include <string.h>\n",
     "\n",
     "#define CAP 1024\n",
     "\n",
@@ -246,52 +676,134 @@ static FILE_HEAD: &str = concat!(
     "        }                                                                           \\\n",
     "        (da)->items[(da)->len++] = (item);                                          \\\n",
     "    } while (0)                                                                     \\\n",
-    "\n",
+    "\n"
+);
+
+/// Everything after the `cell_t` typedef, which `_cc` writes in between.
+static TAPE_HEAD: &str = concat!(
     "typedef struct {\n",
-    "    uint8_t* items;\n",
+    "    cell_t* items;\n",
     "    size_t len;\n",
     "    size_t cap;\n",
     "    size_t ptr;\n",
     "} Tape;\n",
     "\n",
-    "uint8_t tape_curr(Tape* tape) {\n",
+    "cell_t tape_curr(Tape* tape) {\n",
     "    return tape->items[tape->ptr];\n",
     "}\n",
     "\n",
-    "void tape_assign(Tape* tape, uint8_t u8) {\n",
-    "    tape->items[tape->ptr] = u8;\n",
+    "void tape_assign(Tape* tape, cell_t value) {\n",
+    "    tape->items[tape->ptr] = value;\n",
+    "}\n",
+    "\n",
+    "void tape_fail(Tape* tape, const char* what, const char* loc) {\n",
+    "    fprintf(stderr, \"error: %s at %s (tape pointer %zu)\\n\", what, loc, tape->ptr);\n",
+    "    exit(3);\n",
     "}\n",
     "\n",
-    "void tape_shift(Tape* tape, int64_t delta) {\n",
-    "    int64_t ret = (int64_t)tape->ptr + delta;\n",
-    "    if (ret < 0) assert(0 && \"Tape Underflow!\");\n",
+    "cell_t* tape_at(Tape* tape, int64_t offset, const char* loc) {\n",
+    "    int64_t ret = (int64_t)tape->ptr + offset;\n",
+    "    if (ret < 0) tape_fail(tape, \"tape underflow\", loc);\n",
     "    while ((size_t)ret >= tape->len) da_append(tape, 0);\n",
-    "    tape->ptr = (size_t)ret;\n",
+    "    return &tape->items[ret];\n",
     "}\n",
     "\n",
-    "void tape_update(Tape* tape, int64_t delta) {\n",
-    "    tape->items[tape->ptr] += delta;\n",
+    "void tape_shift(Tape* tape, int64_t delta, const char* loc) {\n",
+    "    tape_at(tape, delta, loc);\n",
+    "    tape->ptr = (size_t)((int64_t)tape->ptr + delta);\n",
+    "}\n",
+    "\n",
+    "void tape_set(Tape* tape, int64_t offset, cell_t value, const char* loc) {\n",
+    "    *tape_at(tape, offset, loc) = value;\n",
+    "}\n",
+    "\n",
+    "#define CELL_MAX ((cell_t)-1)\n",
+    "\n",
+    "cell_t cell_add(Tape* tape, cell_t value, int64_t delta, const char* loc) {\n",
+    "#if defined(OVERFLOW_SATURATE) || defined(OVERFLOW_TRAP)\n",
+    "    if (delta < 0 ? value < (uint64_t)-delta : (uint64_t)(CELL_MAX - value) < (uint64_t)delta) {\n",
+    "#ifdef OVERFLOW_TRAP\n",
+    "        tape_fail(tape, \"cell overflow\", loc);\n",
+    "#else\n",
+    "        return delta < 0 ? 0 : CELL_MAX;\n",
+    "#endif\n",
+    "    }\n",
+    "#endif\n",
+    "    (void)tape;\n",
+    "    (void)loc;\n",
+    "    return value + delta;\n",
+    "}\n",
+    "\n",
+    "void tape_update(Tape* tape, int64_t offset, int64_t delta, const char* loc) {\n",
+    "    cell_t* cell = tape_at(tape, offset, loc);\n",
+    "    *cell = cell_add(tape, *cell, delta, loc);\n",
     "}\n",
     "\n",
     "#define tape_jpf(tape, dst) if (tape_curr(tape) == 0) goto dst\n",
     "#define tape_jpb(tape, dst) if (tape_curr(tape) != 0) goto dst\n",
     "\n",
-    "void tape_in(Tape* tape) {\n",
+    "void tape_scan(Tape* tape, int64_t stride, const char* loc) {\n",
+    "    if (tape_curr(tape) == 0) return;\n",
+    "    if (sizeof(cell_t) == 1 && stride == 1) {\n",
+    "        cell_t* found = memchr(&tape->items[tape->ptr], 0, tape->len - tape->ptr);\n",
+    "        if (found == NULL) tape_shift(tape, (int64_t)(tape->len - tape->ptr), loc);\n",
+    "        else tape->ptr = (size_t)(found - tape->items);\n",
+    "    } else if (stride == -1) {\n",
+    "        while (tape_curr(tape) != 0) {\n",
+    "            if (tape->ptr == 0) tape_fail(tape, \"tape underflow\", loc);\n",
+    "            tape->ptr--;\n",
+    "        }\n",
+    "    } else {\n",
+    "        while (tape_curr(tape) != 0) tape_shift(tape, stride, loc);\n",
+    "    }\n",
+    "}\n",
+    "\n",
+    "void tape_in(Tape* tape, int64_t offset, const char* loc) {\n",
+    "    cell_t* cell = tape_at(tape, offset, loc);\n",
     "    int c = fgetc(stdin);\n",
-    "    if (c != EOF) tape_assign(tape, c);\n",
+    "    if (c != EOF) *cell = (cell_t)c;\n",
+    "#if defined(EOF_ZERO)\n",
+    "    else *cell = 0;\n",
+    "#elif defined(EOF_MINUS_ONE)\n",
+    "    else *cell = CELL_MAX;\n",
+    "#elif defined(EOF_ERROR)\n",
+    "    else {\n",
+    "        tape_fail(tape, \"unexpected end of input\", loc);\n",
+    "    }\n",
+    "#endif\n",
     "}\n",
     "\n",
-    "void tape_out(Tape* tape, size_t step) {\n",
+    "void tape_out(Tape* tape, int64_t offset, size_t step, const char* loc) {\n",
+    "    cell_t value = *tape_at(tape, offset, loc);\n",
     "    for (size_t i = 0; i < step; ++i) {\n",
-    "        printf(\"%c\", tape_curr(tape));\n",
+    "        putchar((unsigned char)value);\n",
     "    }\n",
     "}\n",
-    "void tape_add(Tape* tape, size_t delta) {\n",
-    "    tape->items[tape->ptr + delta] += tape_curr(tape);\n",
+    "\n",
+    "cell_t cell_mul_add(Tape* tape, cell_t value, cell_t src, int64_t factor, const char* loc) {\n",
+    "#if defined(OVERFLOW_SATURATE) || defined(OVERFLOW_TRAP)\n",
+    "    uint64_t magnitude = factor < 0 ? -(uint64_t)factor : (uint64_t)factor;\n",
+    "    uint64_t room = factor < 0 ? (uint64_t)value : (uint64_t)(CELL_MAX - value);\n",
+    "    if (magnitude != 0 && src > room / magnitude) {\n",
+    "        return factor < 0 ? cell_add(tape, 0, -1, loc) : cell_add(tape, CELL_MAX, 1, loc);\n",
+    "    }\n",
+    "    if (factor < 0) return (cell_t)(value - (uint64_t)src * magnitude);\n",
+    "#endif\n",
+    "    (void)tape;\n",
+    "    (void)loc;\n",
+    "    return (cell_t)(value + (uint64_t)src * (uint64_t)factor);\n",
     "}\n",
     "\n",
-    "void tape_multiple(Tape* tape, size_t step) {\n",
-    "    tape_assign(tape, (uint8_t)(tape_curr(tape) * step));\n",
+    "void tape_muladd(Tape* tape, int64_t delta, int64_t factor, const char* loc) {\n",
+    "    cell_t src = tape_curr(tape);\n",
+    "    if (src == 0) return;\n",
+    "    cell_t* dst = tape_at(tape, delta, loc);\n",
+    "    *dst = cell_mul_add(tape, *dst, src, factor, loc);\n",
+    "}\n",
+    "\n",
+    "void tape_preset(Tape* tape, size_t start, const cell_t* cells, size_t len) {\n",
+    "    while (tape->len < start + len) da_append(tape, 0);\n",
+    "    memcpy(tape->items + start, cells, len * sizeof(cell_t));\n",
     "}\n",
     "\n",
     "void tape_init(Tape* tape) {\n",
@@ -317,24 +829,51 @@ static MAIN_TAIL: &str = concat!(
 );
 
 impl BfStr {
-    fn _cc(&self, mut write: impl Write, is_optimize: bool) {
-        let ops: Vec<Operation>;
-        if is_optimize {
-            ops = self.optimize();
-        } else {
-            ops = self.ops.clone();
+    fn _cc(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+        options: &CcOptions,
+    ) -> io::Result<OptReport> {
+        if options.lean {
+            return self._cc_lean(write, pipeline, config, options);
         }
+        let (ops, report) = self.optimize_report(pipeline, config);
         let mut cmds: Vec<String> = Vec::new();
         let mut goto_stack: Vec<(usize, &Operation)> = Vec::new();
+        let mut depth = 1;
         for (idx, op) in ops.iter().enumerate() {
-            match op.operator {
-                Op::Upd => cmds.push(format!("    tape_update(&tape, {});\n", op.operand)),
-                Op::Shf => cmds.push(format!("    tape_shift(&tape, {});\n", op.operand)),
-                Op::Acp => cmds.push(format!("    tape_in(&tape);\n")),
-                Op::Out => cmds.push(format!("    tape_out(&tape, {});\n", op.operand)),
+            let indent = "    ".repeat(depth);
+            let stmt = match op.operator {
+                Op::Upd => format!(
+                    "tape_update(&tape, {}, {}, {});",
+                    op.offset,
+                    op.operand,
+                    c_loc(op)
+                ),
+                Op::Shf => format!("tape_shift(&tape, {}, {});", op.operand, c_loc(op)),
+                Op::Scan { stride } => format!("tape_scan(&tape, {}, {});", stride, c_loc(op)),
+                Op::Acp => format!("tape_in(&tape, {}, {});", op.offset, c_loc(op)),
+                Op::Out => format!(
+                    "tape_out(&tape, {}, {}, {});",
+                    op.offset,
+                    op.operand,
+                    c_loc(op)
+                ),
+                Op::Jpf if !options.goto => {
+                    depth += 1;
+                    String::from("while (tape.items[tape.ptr]) {")
+                }
+                Op::Jpb if !options.goto => {
+                    depth -= 1;
+                    cmds.push(format!("{}}}\n", "    ".repeat(depth)));
+                    continue;
+                }
                 Op::Jpf => {
                     cmds.push(String::new());
                     goto_stack.push((idx, op));
+                    continue;
                 }
                 Op::Jpb => match goto_stack.pop() {
                     Some((goto_idx, goto_op)) => {
@@ -349,107 +888,244 @@ impl BfStr {
                             )
                             .as_str(),
                         );
+                        continue;
                     }
                     None => panic!("Unbalanced jump!"),
                 },
-                Op::Set => cmds.push(format!("    tape_assign(&tape, {});\n", op.operand)),
-                Op::Mul => cmds.push(format!("    tape_multiple(&tape, {});\n", op.operand)),
-                // Op::Mov => cmds.push(format!("    tape_move(&tape, {});\n", op.operand)),
-                Op::Add => cmds.push(format!("    tape_add(&tape, {});\n", op.operand)),
+                Op::Set => format!(
+                    "tape_set(&tape, {}, {}, {});",
+                    op.offset,
+                    op.operand,
+                    c_loc(op)
+                ),
+                Op::MulAdd { offset, factor } => {
+                    format!("tape_muladd(&tape, {}, {}, {});", offset, factor, c_loc(op))
+                }
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => {
+                    let mut stmts = Vec::new();
+                    if !output.is_empty() {
+                        stmts.push(format!(
+                            "fwrite({}, 1, {}, stdout);",
+                            c_bytes(output),
+                            output.len()
+                        ));
+                    }
+                    // nobody can look at the tape once the program ends
+                    if idx + 1 < ops.len() {
+                        for run in runs {
+                            let values: Vec<String> =
+                                run.cells.iter().map(|cell| format!("{}u", cell)).collect();
+                            stmts.push(format!(
+                                "tape_preset(&tape, {}, (const cell_t[]){{{}}}, {});",
+                                run.start,
+                                values.join(", "),
+                                run.cells.len()
+                            ));
+                        }
+                        if ptr > 0 {
+                            stmts.push(format!("tape_shift(&tape, {}, \"\");", ptr));
+                        }
+                    }
+                    stmts.join(&format!("\n{}", indent))
+                }
+            };
+            if !stmt.is_empty() {
+                cmds.push(format!("{}{}\n", indent, stmt));
             }
         }
-        let _ = write!(write, "{}", FILE_HEAD);
-        let _ = write!(write, "{}", MAIN_HEAD);
+        write!(write, "{}", FILE_HEAD)?;
+        writeln!(write, "typedef {} cell_t;", config.cell_bits.c_type())?;
+        let policy = config.overflow.to_string().to_uppercase();
+        writeln!(write, "#define OVERFLOW_{}", policy)?;
+        let eof = config.eof.to_string().to_uppercase().replace('-', "_");
+        writeln!(write, "#define EOF_{}\n", eof)?;
+        write!(write, "{}", TAPE_HEAD)?;
+        write!(write, "{}", MAIN_HEAD)?;
         for cmd in &cmds {
-            let _ = write!(write, "{}", cmd);
+            write!(write, "{}", cmd)?;
         }
-        let _ = write!(write, "{}", MAIN_TAIL);
+        write!(write, "{}", MAIN_TAIL)?;
+        Ok(report)
     }
 
-    pub fn cc(&self, save_path: &Path, is_optimize: bool) {
-        let file = File::create(save_path).unwrap();
-        self._cc(file, is_optimize);
+    pub fn cc(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+        options: &CcOptions,
+    ) -> io::Result<OptReport> {
+        let file = File::create(save_path)?;
+        self._cc(file, pipeline, config, options)
+    }
+
+    /// Write the program for `target` to `save_path`, with `c_options`
+    /// only used for C.
+    pub fn compile(
+        &self,
+        target: Target,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+        c_options: &CcOptions,
+    ) -> io::Result<OptReport> {
+        match target {
+            Target::C => self.cc(save_path, pipeline, config, c_options),
+            Target::Llvm => self.llvm(save_path, pipeline, config),
+            Target::X86_64Asm => self.asm(save_path, pipeline, config),
+            Target::Elf => self.elf(save_path, pipeline, config),
+            Target::Wasm => self.wasm(save_path, pipeline, config, false),
+            Target::WasmBinary => self.wasm(save_path, pipeline, config, true),
+            Target::Rust => self.rust(save_path, pipeline, config),
+        }
     }
 }
 
-impl BfStr {
-    fn optimize(&self) -> Vec<Operation> {
-        let mut optimized: Vec<Operation> = Vec::with_capacity(self.ops.len());
-        let mut pos = 0 as usize;
-        while pos < self.ops.len() {
-            let op = &self.ops[pos];
-            match op.operator {
-                /*
-                i know where it jumps to, so i could get the loop sequence
-                then i check the pattern:
-                    if the len of loop sequence is one, and is `-`, that is a reset
-                    if the loop sequence match [(>/<)N (+)M (</>)N -], that is multiple current value with M and plus the value in next N cell,
-                        then move that value to next N cell
-                            to check the pattern inside of the loop, it suppose to have Op::Shf(±N) Op::Upd(M) Op::Bwd(∓N) Op::Upd(-1)
-                            then these instructions along with the jumps, can be replace with: 1) Mul(M) 2) Add(N) 3) Set(0)
-                        additionally, [(>/<)N (-)M (</>)N -] is similar to divide
-                 */
-                Op::Jpf => {
-                    let loop_len = op.operand - &self.ops[op.operand as usize - 1].operand - 1;
-                    if loop_len == 1
-                        && self.ops[pos + 1]
-                            == (Operation {
-                                operator: Op::Upd,
-                                operand: -1,
-                            })
-                    {
-                        optimized.push(Operation {
-                            operator: Op::Set,
-                            operand: 0,
-                        });
-                        pos += 3;
-                    } else if loop_len == 4 {
-                        let loop_op: Vec<Op> = self.ops[pos + 1..pos + 5]
-                            .iter()
-                            .map(|op| op.operator.clone())
-                            .collect();
-                        if loop_op == vec![Op::Shf, Op::Upd, Op::Shf, Op::Upd]
-                            && self.ops[pos + 1].operand == -self.ops[pos + 3].operand
-                            && self.ops[pos + 2].operand > 0
-                            && self.ops[pos + 4].operand == -1
-                        {
-                            optimized.extend([
-                                Operation {
-                                    operator: Op::Mul,
-                                    operand: self.ops[pos + 2].operand,
-                                },
-                                Operation {
-                                    operator: Op::Add,
-                                    operand: self.ops[pos + 1].operand,
-                                },
-                                Operation {
-                                    operator: Op::Set,
-                                    operand: 0,
-                                },
-                            ]);
-                            pos += 6;
-                        } else {
-                            optimized.push(op.clone());
-                            pos += 1;
-                        }
-                    } else {
-                        optimized.push(op.clone());
-                        pos += 1;
-                    }
-                }
-                _ => {
-                    optimized.push(op.clone());
-                    pos += 1;
-                }
-            }
+/// How the C backend writes its code.
+#[derive(Debug, Clone)]
+pub struct CcOptions {
+    /// Build loops out of `goto` labels instead of `while` blocks, like
+    /// older versions did. Not for `lean`, which always uses `while`.
+    pub goto: bool,
+    /// Write a fixed `static` tape and a bare cell pointer, with every op
+    /// inline, instead of calling into the growable tape runtime.
+    pub lean: bool,
+    /// How many cells the `lean` tape has.
+    pub tape_cells: usize,
+    /// Have the `lean` code stop with an error when it leaves the tape,
+    /// instead of reading and writing whatever memory is there.
+    pub bounds_check: bool,
+}
+
+impl Default for CcOptions {
+    fn default() -> Self {
+        CcOptions {
+            goto: false,
+            lean: false,
+            tape_cells: 30000,
+            bounds_check: false,
         }
-        optimized
+    }
+}
+
+/// What `compile` turns a program into.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Target {
+    /// C source for any C compiler.
+    #[default]
+    C,
+    /// Textual LLVM IR for `lli`, `llc` or `clang`.
+    Llvm,
+    /// GAS assembly for x86-64 Linux, with no libc.
+    X86_64Asm,
+    /// A static x86-64 Linux executable, ready to run.
+    Elf,
+    /// A WASI module as WAT text.
+    Wasm,
+    /// A WASI module in the binary format.
+    WasmBinary,
+    /// A self-contained `main.rs` for `rustc`.
+    Rust,
+}
+
+impl Target {
+    /// The extension of the file `compile` writes, if any.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Target::C => "c",
+            Target::Llvm => "ll",
+            Target::X86_64Asm => "s",
+            Target::Elf => "",
+            Target::Wasm => "wat",
+            Target::WasmBinary => "wasm",
+            Target::Rust => "rs",
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(Target::C),
+            "llvm" => Ok(Target::Llvm),
+            "x86_64-asm" => Ok(Target::X86_64Asm),
+            "elf" => Ok(Target::Elf),
+            "wasm" => Ok(Target::Wasm),
+            "wasm-binary" => Ok(Target::WasmBinary),
+            "rust" => Ok(Target::Rust),
+            _ => Err(format!(
+                "unknown target `{}`, expect c, llvm, x86_64-asm, elf, wasm, wasm-binary or rust",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::C => write!(f, "c"),
+            Target::Llvm => write!(f, "llvm"),
+            Target::X86_64Asm => write!(f, "x86_64-asm"),
+            Target::Elf => write!(f, "elf"),
+            Target::Wasm => write!(f, "wasm"),
+            Target::WasmBinary => write!(f, "wasm-binary"),
+            Target::Rust => write!(f, "rust"),
+        }
+    }
+}
+
+/// The source location of `op` as a C string literal, for runtime errors.
+fn c_loc(op: &Operation) -> String {
+    format!("\"line {}, column {}\"", op.span.line, op.span.column)
+}
+
+/// `bytes` as a C string literal, split into one literal per line.
+fn c_bytes(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for (idx, &byte) in bytes.iter().enumerate() {
+        match byte {
+            b'"' | b'\\' | b'?' => literal.push_str(&format!("\\{}", byte as char)),
+            b'\n' => literal.push_str("\\n"),
+            b' '..=b'~' => literal.push(byte as char),
+            // always 3 digits, so a digit after it isn't taken as part of it
+            _ => literal.push_str(&format!("\\{:03o}", byte)),
+        }
+        if byte == b'\n' && idx + 1 < bytes.len() {
+            literal.push_str("\"\n        \"");
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+impl BfStr {
+    fn optimize(&self, pipeline: &Pipeline, config: &Config) -> Vec<Operation> {
+        self.optimize_report(pipeline, config).0
+    }
+
+    fn optimize_report(&self, pipeline: &Pipeline, config: &Config) -> (Vec<Operation>, OptReport) {
+        pipeline.run(self.ops.clone(), config)
+    }
+
+    /// The ops after `pipeline`, listed in the given format.
+    pub fn dump_ir(&self, pipeline: &Pipeline, config: &Config, format: IrFormat) -> String {
+        dump::dump(&self.optimize(pipeline, config), format)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::bf_str::BfStr;
+    use crate::bf_str::{
+        build, execute, BfStr, Bracket, BuildOptions, CcOptions, CellBits, Config, Eof,
+        InterpretError, IrFormat, Op, Overflow, Pass, Pipeline, Run, Span,
+    };
     use std::io::{self, Write};
     use std::path::Path;
 
@@ -480,9 +1156,23 @@ mod tests {
             let bf_str = BfStr::from_file(path)?;
             let mut ret = Vec::new();
             if input.is_empty() {
-                bf_str._interpret(io::stdin(), &mut ret);
+                bf_str
+                    ._interpret(
+                        &Pipeline::default(),
+                        &Config::default(),
+                        io::stdin(),
+                        &mut ret,
+                    )
+                    .unwrap();
             } else {
-                bf_str._interpret(input.as_bytes(), &mut ret);
+                bf_str
+                    ._interpret(
+                        &Pipeline::default(),
+                        &Config::default(),
+                        input.as_bytes(),
+                        &mut ret,
+                    )
+                    .unwrap();
             }
             assert_eq!(ret, output.as_bytes());
         }
@@ -490,18 +1180,271 @@ mod tests {
         Ok(())
     }
 
-    use std::process::{Command, Stdio};
+    #[test]
+    fn test_parse_error() {
+        let err = BfStr::parse("+[\n+]]-").unwrap_err();
+        assert_eq!(err.bracket, Bracket::Close);
+        assert_eq!((err.line, err.column), (2, 3));
+        assert_eq!(
+            err.to_string(),
+            "unmatched `]` at line 2, column 3\n  |\n2 | +]]-\n  |   ^"
+        );
+
+        let err = BfStr::parse("[[-]\n>+").unwrap_err();
+        assert_eq!(err.bracket, Bracket::Open);
+        assert_eq!((err.line, err.column), (1, 1));
+    }
+
+    #[test]
+    fn test_span() {
+        let source = "+++ ++\n[-]>\u{e9}.";
+        let bf_str = BfStr::parse(source).unwrap();
+        let spans: Vec<Span> = bf_str.ops.iter().map(|op| op.span).collect();
+        assert_eq!(&source[spans[0].start..spans[0].end], "+++ ++");
+        assert_eq!((spans[1].line, spans[1].column), (2, 1));
+        // the `é` comment is two bytes wide
+        assert_eq!(spans[5].start, 13);
+        assert_eq!((spans[5].line, spans[5].column), (2, 6));
+
+        // merged ops cover every source op they replace
+        let (optimized, _) = bf_str.optimize_report(&Pipeline::level(2), &Config::default());
+        assert_eq!(optimized[1].operator, Op::Set);
+        assert_eq!(
+            &source[optimized[1].span.start..optimized[1].span.end],
+            "[-]"
+        );
+    }
+
+    #[test]
+    fn test_interpret_error() {
+        let bf_str = BfStr::parse("+>\n+<<+").unwrap();
+        let err = bf_str
+            ._interpret(
+                &Pipeline::default(),
+                &Config::default(),
+                io::empty(),
+                io::sink(),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InterpretError::TapeUnderflow {
+                op: 3,
+                span: Span {
+                    line: 2,
+                    column: 2,
+                    ..
+                },
+                ptr: 1,
+            }
+        ));
+
+        let bf_str = BfStr::parse("+[]").unwrap();
+        let config = Config {
+            step_limit: Some(100),
+            ..Config::default()
+        };
+        let err = bf_str
+            ._interpret(&Pipeline::default(), &config, io::empty(), io::sink())
+            .unwrap_err();
+        assert!(matches!(err, InterpretError::StepLimit { limit: 100, .. }));
+
+        // output is buffered, so the failed write surfaces at the flush
+        // before `,`
+        let bf_str = BfStr::parse("+.,").unwrap();
+        let err = bf_str
+            ._interpret(
+                &Pipeline::default(),
+                &Config::default(),
+                io::empty(),
+                &mut [][..],
+            )
+            .unwrap_err();
+        assert!(matches!(err, InterpretError::Io { op: 2, .. }));
+    }
+
+    use std::process::{Command, Output, Stdio};
     use tempfile::NamedTempFile;
 
     #[test]
     fn test_cc() -> io::Result<()> {
-        _test_cc(false)?;
-        _test_cc(true)?;
+        for goto in [false, true] {
+            let options = CcOptions {
+                goto,
+                ..CcOptions::default()
+            };
+            for level in 0..=3 {
+                _test_cc(level, &options)?;
+            }
+        }
+
+        // nested loops as nested, indented blocks, or labels
+        let bf_str = BfStr::parse("+[>+[-]<-]").unwrap();
+        let mut c_code = Vec::new();
+        bf_str._cc(
+            &mut c_code,
+            &Pipeline::level(0),
+            &Config::default(),
+            &CcOptions::default(),
+        )?;
+        let c_code = String::from_utf8(c_code).unwrap();
+        assert!(c_code.contains(concat!(
+            "    while (tape.items[tape.ptr]) {\n",
+            "        tape_shift(&tape, 1, \"line 1, column 3\");\n",
+            "        tape_update(&tape, 0, 1, \"line 1, column 4\");\n",
+            "        while (tape.items[tape.ptr]) {\n",
+            "            tape_update(&tape, 0, -1, \"line 1, column 6\");\n",
+            "        }\n",
+        )));
+        let main = &c_code[c_code.find("int main(void)").unwrap()..];
+        assert!(!main.contains("goto") && !main.contains("tape_jp"));
+        let mut c_code = Vec::new();
+        bf_str._cc(
+            &mut c_code,
+            &Pipeline::level(0),
+            &Config::default(),
+            &CcOptions {
+                goto: true,
+                ..CcOptions::default()
+            },
+        )?;
+        let c_code = String::from_utf8(c_code).unwrap();
+        assert!(c_code.contains("    tape_jpf(&tape, jpf7);\n    jpb5:\n"));
+        assert!(!c_code.contains("while (tape.items"));
+
+        // `[<]` stays within ISO C, with no `memrchr`
+        let bf_str = BfStr::parse(">+>+>+[<]>.").unwrap();
+        let ret = gcc_run(&bf_str, 3, &Config::default(), b"")?;
+        assert_eq!(ret.stdout, b"\x01");
+        let mut c_code = Vec::new();
+        bf_str._cc(
+            &mut c_code,
+            &Pipeline::level(3),
+            &Config::default(),
+            &CcOptions::default(),
+        )?;
+        let c_code = String::from_utf8(c_code).unwrap();
+        assert!(!c_code.contains("_GNU_SOURCE") && !c_code.contains("memrchr"));
+
+        // errors, underflow included, exit with 3 like every other backend
+        check_native(|bf_str, level, config, input| gcc_run(bf_str, level, config, input).map(Some))
+    }
+
+    #[test]
+    fn test_cc_lean() -> io::Result<()> {
+        let lean = CcOptions {
+            lean: true,
+            ..CcOptions::default()
+        };
+        for level in 0..=3 {
+            _test_cc(level, &lean)?;
+        }
+        let checked = CcOptions {
+            bounds_check: true,
+            ..lean.clone()
+        };
+        check_native(|bf_str, level, config, input| {
+            gcc_run_with(bf_str, level, config, &checked, input).map(Some)
+        })?;
+
+        // every op inline on a plain pointer, with no runtime to call
+        let bf_str = BfStr::parse("++>+++<[->++<]>.").unwrap();
+        let mut c_code = Vec::new();
+        bf_str._cc(&mut c_code, &Pipeline::level(2), &Config::default(), &lean)?;
+        let c_code = String::from_utf8(c_code).unwrap();
+        assert!(c_code.contains("static uint8_t tape[TAPE_CELLS];\nstatic uint8_t *p = tape;\n"));
+        assert!(c_code.contains(concat!(
+            "    *p += 2;\n",
+            "    p[1] += 3;\n",
+            "    if (*p) p[1] += *p * 2u;\n",
+            "    *p = 0;\n",
+            "    putchar(p[1]);\n",
+            "    p += 1;\n",
+        )));
+        assert!(!c_code.contains("CHECK") && !c_code.contains("fail("));
+
+        // saturating reports nothing, so there's no `fail` to leave unused
+        let saturate = Config {
+            overflow: Overflow::Saturate,
+            ..Config::default()
+        };
+        let overflow = BfStr::parse("->++++++[<+++++++++++>-]<.").unwrap();
+        let ret = gcc_run_with(&overflow, 3, &saturate, &lean, b"")?;
+        assert_eq!(ret.stdout, b"B");
+        let mut c_code = Vec::new();
+        overflow._cc(&mut c_code, &Pipeline::level(3), &saturate, &lean)?;
+        assert!(!String::from_utf8(c_code).unwrap().contains("fail("));
+
+        // a small tape runs out, and a preset that doesn't fit is refused
+        let small = CcOptions {
+            tape_cells: 4,
+            ..checked
+        };
+        let ret = gcc_run_with(
+            &BfStr::parse("+[>+]").unwrap(),
+            0,
+            &Config::default(),
+            &small,
+            b"",
+        )?;
+        assert_eq!(ret.status.code(), Some(3));
+        assert!(String::from_utf8_lossy(&ret.stderr)
+            .starts_with("error: end of tape at line 1, column 3"));
+        let err = BfStr::parse(">>>>+,")
+            .unwrap()
+            ._cc(io::sink(), &Pipeline::level(3), &Config::default(), &small)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
         Ok(())
     }
 
-    fn _test_cc(is_optimize: bool) -> io::Result<()> {
-        let test_case = [
+    #[test]
+    fn test_build() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let exec = dir.path().join("program");
+        for (path, input, output) in &samples() {
+            let bf_str = BfStr::from_file(path)?;
+            bf_str
+                .build(
+                    &exec,
+                    &Pipeline::level(3),
+                    &Config::default(),
+                    &BuildOptions::default(),
+                )
+                .unwrap();
+            let ret = run_with_input(&mut Command::new(&exec), input.as_bytes())?;
+            assert_eq!(ret.stdout, output.as_bytes());
+        }
+
+        // a compiler error keeps the C around to look at
+        let options = BuildOptions {
+            cflags: vec![String::from("-Werror=no-such-warning")],
+            ..BuildOptions::default()
+        };
+        let err = BfStr::parse("+.")
+            .unwrap()
+            .build(&exec, &Pipeline::level(0), &Config::default(), &options)
+            .unwrap_err();
+        let build::BuildError::Compiler { status, c_file, .. } = err else {
+            panic!("expected a compiler error, got {:?}", err);
+        };
+        assert!(!status.success());
+        assert!(std::fs::read_to_string(&c_file)?.contains("int main(void)"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(c_file.parent().unwrap())?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        std::fs::remove_dir_all(c_file.parent().unwrap())?;
+        Ok(())
+    }
+
+    /// Every sample with its input and expected output.
+    fn samples() -> [(&'static Path, &'static str, &'static str); 4] {
+        [
             (Path::new("./sample/hello.bf"), "", "Hello World!\n"),
             (Path::new("./sample/392quine.bf"), "", QUINE),
             (
@@ -510,38 +1453,975 @@ mod tests {
                 "U\nr\ny\ny\nb\n\x04\n",
             ),
             (Path::new("./sample/simplify.bf"), "", "A\nA\nA"),
-        ];
+        ]
+    }
 
-        for (path, input, output) in &test_case {
-            let temp_file = NamedTempFile::new()?;
+    fn _test_cc(level: u8, options: &CcOptions) -> io::Result<()> {
+        for (path, input, output) in &samples() {
             let bf_str = BfStr::from_file(path)?;
-            bf_str.cc(temp_file.path(), is_optimize);
-
-            let temp_exec = NamedTempFile::new()?;
-            let exit_status = Command::new("gcc")
-                .args([
-                    "-x",
-                    "c",
-                    "-o",
-                    temp_exec.path().to_str().unwrap(),
-                    temp_file.path().to_str().unwrap(),
-                ])
-                .status()?;
-            assert!(exit_status.success());
-
-            let temp_exec = temp_exec.into_temp_path();
-            let mut child = Command::new(temp_exec.to_str().unwrap())
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()?;
-            if !input.is_empty() {
-                let mut stdin = child.stdin.take().unwrap();
-                stdin.write_all(input.as_bytes())?;
-                drop(stdin);
+            let ret = gcc_run_with(
+                &bf_str,
+                level,
+                &Config::default(),
+                options,
+                input.as_bytes(),
+            )?;
+            assert_eq!(ret.stdout, output.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    /// Compile `bf_str` to C, build it with gcc and run it on `input`.
+    fn gcc_run(bf_str: &BfStr, level: u8, config: &Config, input: &[u8]) -> io::Result<Output> {
+        gcc_run_with(bf_str, level, config, &CcOptions::default(), input)
+    }
+
+    fn gcc_run_with(
+        bf_str: &BfStr,
+        level: u8,
+        config: &Config,
+        options: &CcOptions,
+        input: &[u8],
+    ) -> io::Result<Output> {
+        let temp_file = NamedTempFile::new()?;
+        bf_str.cc(temp_file.path(), &Pipeline::level(level), config, options)?;
+
+        let temp_exec = NamedTempFile::new()?;
+        // warnings too, since users build this with their own flags
+        let exit_status = Command::new("gcc")
+            .args([
+                "-Wall",
+                "-Wextra",
+                "-Werror",
+                "-x",
+                "c",
+                "-o",
+                temp_exec.path().to_str().unwrap(),
+                temp_file.path().to_str().unwrap(),
+            ])
+            .status()?;
+        assert!(exit_status.success());
+
+        let temp_exec = temp_exec.into_temp_path();
+        run_with_input(&mut Command::new(temp_exec.to_str().unwrap()), input)
+    }
+
+    /// Run `command` with `input` on stdin, collecting stdout and stderr.
+    fn run_with_input(command: &mut Command, input: &[u8]) -> io::Result<Output> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if !input.is_empty() {
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(input)?;
+            drop(stdin);
+        }
+        child.wait_with_output()
+    }
+
+    /// Compile `bf_str` to LLVM IR and run it with `lli` on `input`, or
+    /// `None` if `lli` isn't installed.
+    fn lli_run(
+        bf_str: &BfStr,
+        level: u8,
+        config: &Config,
+        input: &[u8],
+    ) -> io::Result<Option<Output>> {
+        let Ok(version) = Command::new("lli").arg("--version").output() else {
+            return Ok(None);
+        };
+        // `ptr` needs a flag before LLVM 15, which is gone again by LLVM 17
+        let version = String::from_utf8_lossy(&version.stdout);
+        let major: u32 = version
+            .split("LLVM version ")
+            .nth(1)
+            .and_then(|rest| rest.split('.').next())
+            .and_then(|major| major.trim().parse().ok())
+            .unwrap_or(u32::MAX);
+        let temp_file = NamedTempFile::new()?;
+        bf_str.llvm(temp_file.path(), &Pipeline::level(level), config)?;
+        let mut command = Command::new("lli");
+        if major < 15 {
+            command.arg("-opaque-pointers");
+        }
+        command.arg(temp_file.path());
+        run_with_input(&mut command, input).map(Some)
+    }
+
+    /// Compile `bf_str` to assembly and run it on `input` after `as` and
+    /// `ld`, or `None` if they aren't installed.
+    fn asm_run(
+        bf_str: &BfStr,
+        level: u8,
+        config: &Config,
+        input: &[u8],
+    ) -> io::Result<Option<Output>> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("prog.s");
+        let object = temp_dir.path().join("prog.o");
+        let exec = temp_dir.path().join("prog");
+        bf_str.asm(&source, &Pipeline::level(level), config)?;
+        let Ok(status) = Command::new("as")
+            .arg(&source)
+            .arg("-o")
+            .arg(&object)
+            .status()
+        else {
+            return Ok(None);
+        };
+        assert!(status.success());
+        let Ok(status) = Command::new("ld")
+            .arg(&object)
+            .arg("-o")
+            .arg(&exec)
+            .status()
+        else {
+            return Ok(None);
+        };
+        assert!(status.success());
+        run_with_input(&mut Command::new(&exec), input).map(Some)
+    }
+
+    /// Compile `bf_str` to a binary WASI module and run it with `wasmtime`
+    /// on `input`, or `None` if `wasmtime` isn't installed.
+    fn wasmtime_run(
+        bf_str: &BfStr,
+        level: u8,
+        config: &Config,
+        input: &[u8],
+    ) -> io::Result<Option<Output>> {
+        if Command::new("wasmtime").arg("--version").output().is_err() {
+            return Ok(None);
+        }
+        let temp_dir = tempfile::tempdir()?;
+        let module = temp_dir.path().join("prog.wasm");
+        bf_str.wasm(&module, &Pipeline::level(level), config, true)?;
+        run_with_input(Command::new("wasmtime").arg(&module), input).map(Some)
+    }
+
+    /// Compile `bf_str` to Rust and run it on `input` after `rustc`, or
+    /// `None` if `rustc` isn't installed.
+    fn rustc_run(
+        bf_str: &BfStr,
+        level: u8,
+        config: &Config,
+        input: &[u8],
+    ) -> io::Result<Option<Output>> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("main.rs");
+        let exec = temp_dir.path().join("main");
+        bf_str.rust(&source, &Pipeline::level(level), config)?;
+        let Ok(ret) = Command::new("rustc")
+            .args(["--edition", "2021", "-O", "-o"])
+            .arg(&exec)
+            .arg(&source)
+            .output()
+        else {
+            return Ok(None);
+        };
+        assert!(
+            ret.status.success(),
+            "{}",
+            String::from_utf8_lossy(&ret.stderr)
+        );
+        // the generated code should build cleanly
+        assert!(
+            ret.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&ret.stderr)
+        );
+        run_with_input(&mut Command::new(&exec), input).map(Some)
+    }
+
+    #[test]
+    fn test_cell_bits() -> io::Result<()> {
+        // prints `A` only if 16 * 16 doesn't wrap to zero
+        let bf_str = BfStr::parse(concat!(
+            "++++++++++++++++[>++++++++++++++++<-]>",
+            "[[-]>++++++++[<++++++++>-]<+.[-]]",
+        ))
+        .unwrap();
+        for (cell_bits, output) in [
+            (CellBits::U8, ""),
+            (CellBits::U16, "A"),
+            (CellBits::U32, "A"),
+            (CellBits::U64, "A"),
+        ] {
+            let config = Config {
+                cell_bits,
+                ..Config::default()
+            };
+            let mut ret = Vec::new();
+            bf_str
+                ._interpret(&Pipeline::default(), &config, io::empty(), &mut ret)
+                .unwrap();
+            assert_eq!(ret, output.as_bytes());
+            assert_eq!(gcc_run(&bf_str, 0, &config, b"")?.stdout, output.as_bytes());
+            assert_eq!(gcc_run(&bf_str, 3, &config, b"")?.stdout, output.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflow() -> io::Result<()> {
+        // 255 + 66 or 0 + 66
+        let underflow = BfStr::parse("#\n->++++++[<+++++++++++>-]<.").unwrap();
+        // 64 * 4 through a multiply loop, then 0 - 190 or 255 - 190
+        let multiply = BfStr::parse(concat!(
+            "++++++++[>++++++++<-]>[->++++<]",
+            "++++++++++[>-------------------<-]>."
+        ))
+        .unwrap();
+        for (bf_str, overflow, output) in [
+            (&underflow, Overflow::Wrap, Some("A")),
+            (&underflow, Overflow::Saturate, Some("B")),
+            (&underflow, Overflow::Trap, None),
+            (&multiply, Overflow::Wrap, Some("B")),
+            (&multiply, Overflow::Saturate, Some("A")),
+            (&multiply, Overflow::Trap, None),
+        ] {
+            let config = Config {
+                overflow,
+                ..Config::default()
+            };
+            for level in [0, 3] {
+                let mut ret = Vec::new();
+                let result =
+                    bf_str._interpret(&Pipeline::level(level), &config, io::empty(), &mut ret);
+                match output {
+                    Some(output) => {
+                        result.unwrap();
+                        assert_eq!(ret, output.as_bytes());
+                    }
+                    None => assert!(matches!(result, Err(InterpretError::Overflow { .. }))),
+                }
+                let c_ret = gcc_run(bf_str, level, &config, b"")?;
+                match output {
+                    Some(output) => assert_eq!(c_ret.stdout, output.as_bytes()),
+                    None => {
+                        assert_eq!(c_ret.status.code(), Some(3));
+                        assert!(String::from_utf8_lossy(&c_ret.stderr).contains("cell overflow"));
+                    }
+                }
+            }
+        }
+
+        let err = underflow
+            ._interpret(
+                &Pipeline::default(),
+                &Config {
+                    overflow: Overflow::Trap,
+                    ..Config::default()
+                },
+                io::empty(),
+                io::sink(),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InterpretError::Overflow {
+                op: 0,
+                span: Span {
+                    line: 2,
+                    column: 1,
+                    ..
+                },
+                ptr: 0,
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_eof() -> io::Result<()> {
+        // a NUL byte is read as 0, then the cell holding 1 hits EOF
+        let bf_str = BfStr::parse(concat!(
+            "+,>++++++++[<++++++++>-]<+.",
+            ">+,>++++++[<+++++++++++>-]<."
+        ))
+        .unwrap();
+        for (eof, output) in [
+            (Eof::Unchanged, Some("AC")),
+            (Eof::Zero, Some("AB")),
+            (Eof::MinusOne, Some("AA")),
+            (Eof::Error, None),
+        ] {
+            let config = Config {
+                eof,
+                ..Config::default()
+            };
+            let mut ret = Vec::new();
+            let result = bf_str._interpret(&Pipeline::default(), &config, &b"\0"[..], &mut ret);
+            let c_ret = gcc_run(&bf_str, 0, &config, b"\0")?;
+            match output {
+                Some(output) => {
+                    result.unwrap();
+                    assert_eq!(ret, output.as_bytes());
+                    assert_eq!(c_ret.stdout, output.as_bytes());
+                }
+                None => {
+                    assert!(matches!(
+                        result,
+                        Err(InterpretError::UnexpectedEof { op: 15, .. })
+                    ));
+                    assert_eq!(c_ret.status.code(), Some(3));
+                    assert_eq!(c_ret.stdout, b"A");
+                }
             }
-            assert_eq!(child.wait_with_output().unwrap().stdout, output.as_bytes());
         }
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_bytes() -> io::Result<()> {
+        let bf_str = BfStr::parse(".+[.+]").unwrap();
+        let expected: Vec<u8> = (0..=255).collect();
+        let mut ret = Vec::new();
+        bf_str
+            ._interpret(
+                &Pipeline::default(),
+                &Config::default(),
+                io::empty(),
+                &mut ret,
+            )
+            .unwrap();
+        assert_eq!(ret, expected);
+        // optimized, it all becomes one string literal
+        for level in [0, 3] {
+            assert_eq!(
+                gcc_run(&bf_str, level, &Config::default(), b"")?.stdout,
+                ret
+            );
+        }
+        Ok(())
+    }
 
+    #[test]
+    fn test_optimized_interpret() -> io::Result<()> {
+        for entry in std::fs::read_dir("./sample")? {
+            let bf_str = BfStr::from_file(&entry?.path())?;
+            let config = Config::default();
+            // every level, and every pass on its own
+            let mut pipelines: Vec<Pipeline> = (0..=3).map(Pipeline::level).collect();
+            pipelines.extend(Pass::ALL.into_iter().map(|pass| Pipeline {
+                passes: vec![pass],
+                ..Pipeline::default()
+            }));
+            let mut runs = Vec::new();
+            for pipeline in &pipelines {
+                let ops = bf_str.optimize(pipeline, &config);
+                let mut ret = Vec::new();
+                let mut tape = execute::<u8>(&ops, &config, &b"Hello\x04"[..], &mut ret).unwrap();
+                // the tape only ever grows, so ignore how far it got
+                while tape.last() == Some(&0) {
+                    tape.pop();
+                }
+                runs.push((ret, tape));
+            }
+            for (pipeline, run) in pipelines.iter().zip(&runs) {
+                assert_eq!(&runs[0], run, "{:?}", pipeline.passes);
+            }
+        }
         Ok(())
     }
+
+    #[test]
+    fn test_balanced_loop() -> io::Result<()> {
+        let bf_str = BfStr::parse("+++++[->+>++>>---<<<<]>.>.>.>.[-<<<+>>>]<<<.").unwrap();
+        let (optimized, _) = bf_str.optimize_report(&Pipeline::level(2), &Config::default());
+        assert!(!optimized
+            .iter()
+            .any(|op| op.operator == Op::Jpf || op.operator == Op::Jpb));
+        // one multiply-add per target, then the clear
+        let lowered: Vec<Op> = optimized[1..5]
+            .iter()
+            .map(|op| op.operator.clone())
+            .collect();
+        assert_eq!(
+            lowered,
+            [
+                Op::MulAdd {
+                    offset: 1,
+                    factor: 1
+                },
+                Op::MulAdd {
+                    offset: 2,
+                    factor: 2
+                },
+                Op::MulAdd {
+                    offset: 4,
+                    factor: -3
+                },
+                Op::Set,
+            ]
+        );
+
+        for overflow in [Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            let config = Config {
+                overflow,
+                ..Config::default()
+            };
+            let mut runs = Vec::new();
+            for ops in [&bf_str.ops, &optimized] {
+                let mut ret = Vec::new();
+                // ops differ, so only compare whether it failed, not where
+                let tape = execute::<u8>(ops, &config, io::empty(), &mut ret)
+                    .ok()
+                    .map(|tape| tape[..8].to_vec());
+                runs.push(format!("{:?} {:?}", tape, ret));
+            }
+            assert_eq!(runs[0], runs[1]);
+        }
+        let c_ret = gcc_run(&bf_str, 3, &Config::default(), b"")?;
+        assert_eq!(c_ret.stdout, [5, 10, 0, 241, 246]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> io::Result<()> {
+        let bf_str = BfStr::parse(">+>+>+>>+<<<<[>]+[<]>[>>>]++++++++[<++++++++>-]<+.").unwrap();
+        let (optimized, _) = bf_str.optimize_report(&Pipeline::level(2), &Config::default());
+        let strides: Vec<isize> = optimized
+            .iter()
+            .filter_map(|op| match op.operator {
+                Op::Scan { stride } => Some(stride),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(strides, [1, -1, 3]);
+
+        for cell_bits in [CellBits::U8, CellBits::U16] {
+            let config = Config {
+                cell_bits,
+                ..Config::default()
+            };
+            let mut ret = Vec::new();
+            bf_str
+                ._interpret(&Pipeline::level(3), &config, io::empty(), &mut ret)
+                .unwrap();
+            assert_eq!(ret, b"A");
+            assert_eq!(gcc_run(&bf_str, 3, &config, b"")?.stdout, b"A");
+        }
+
+        // running off the left end is still an underflow
+        let bf_str = BfStr::parse("+>+[<]").unwrap();
+        let err = bf_str
+            ._interpret(
+                &Pipeline::level(3),
+                &Config::default(),
+                io::empty(),
+                io::sink(),
+            )
+            .unwrap_err();
+        // `+>+` is worked out at compile time, so the scan is op 1
+        assert!(matches!(err, InterpretError::TapeUnderflow { op: 1, .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_offsets() -> io::Result<()> {
+        let bf_str = BfStr::parse(">+>+>->>+<<<<[>.<-]").unwrap();
+        assert_eq!(bf_str.ops.len(), 15);
+        let (optimized, _) = bf_str.optimize_report(&Pipeline::level(2), &Config::default());
+        let ops: Vec<(Op, isize, isize)> = optimized
+            .iter()
+            .map(|op| (op.operator.clone(), op.operand, op.offset))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                (Op::Upd, 1, 1),
+                (Op::Upd, 1, 2),
+                (Op::Upd, -1, 3),
+                (Op::Upd, 1, 5),
+                (Op::Shf, 1, 0),
+                (Op::Jpf, 9, 0),
+                (Op::Out, 1, 1),
+                (Op::Upd, -1, 0),
+                (Op::Jpb, 6, 0),
+            ]
+        );
+
+        let mut ret = Vec::new();
+        bf_str
+            ._interpret(
+                &Pipeline::level(3),
+                &Config::default(),
+                io::empty(),
+                &mut ret,
+            )
+            .unwrap();
+        assert_eq!(ret, b"\x01");
+        assert_eq!(
+            gcc_run(&bf_str, 3, &Config::default(), b"")?.stdout,
+            b"\x01"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel() {
+        let op_count = |source| BfStr::parse(source).unwrap().ops.len();
+        // without cancelling these would be 2, 2, 2 and 12 ops
+        assert_eq!(op_count("+-"), 0);
+        assert_eq!(op_count("><"), 0);
+        assert_eq!(op_count("+++--"), 1);
+        assert_eq!(op_count("+>-<-+<>[+-]"), 6);
+
+        let bf_str = BfStr::parse("+++ x --\n>><<< .").unwrap();
+        let ops: Vec<(Op, isize)> = bf_str
+            .ops
+            .iter()
+            .map(|op| (op.operator.clone(), op.operand))
+            .collect();
+        assert_eq!(ops, [(Op::Upd, 1), (Op::Shf, -1), (Op::Out, 1)]);
+        assert_eq!((bf_str.ops[0].span.start, bf_str.ops[0].span.end), (0, 8));
+    }
+
+    #[test]
+    fn test_dead_loops() -> io::Result<()> {
+        let bf_str = BfStr::parse(concat!(
+            "[a comment, with . and ,]",
+            "+[-][dead]",
+            "++[->+<][dead[nested]]",
+            "+[.-][dead]",
+            ">[.-]",
+        ))
+        .unwrap();
+        let (optimized, report) = bf_str.optimize_report(&Pipeline::level(2), &Config::default());
+        assert_eq!(report.dead_loops, 4);
+        let loops = optimized.iter().filter(|op| op.operator == Op::Jpf).count();
+        assert_eq!(loops, 2);
+
+        let mut ret = Vec::new();
+        bf_str
+            ._interpret(
+                &Pipeline::level(3),
+                &Config::default(),
+                io::empty(),
+                &mut ret,
+            )
+            .unwrap();
+        assert_eq!(ret, b"\x01\x02\x01");
+        let c_ret = gcc_run(&bf_str, 3, &Config::default(), b"")?;
+        assert_eq!(c_ret.stdout, b"\x01\x02\x01");
+        Ok(())
+    }
+
+    #[test]
+    fn test_const_fold() -> io::Result<()> {
+        // a program that reads nothing is only its output
+        let bf_str = BfStr::from_file(Path::new("./sample/hello.bf"))?;
+        let optimized = bf_str.optimize(&Pipeline::level(3), &Config::default());
+        assert_eq!(optimized.len(), 1);
+        assert!(matches!(
+            &optimized[0].operator,
+            Op::Preset { output, .. } if output == b"Hello World!\n"
+        ));
+        let mut c_code = Vec::new();
+        bf_str._cc(
+            &mut c_code,
+            &Pipeline::level(3),
+            &Config::default(),
+            &CcOptions::default(),
+        )?;
+        let c_code = String::from_utf8(c_code).unwrap();
+        let main = &c_code[c_code.find("int main(void)").unwrap()..];
+        assert_eq!(main.matches("fwrite(").count(), 1);
+        assert!(!main.contains("tape_update"));
+
+        let bf_str = BfStr::parse("++++++++[>++++++++<-]>+.,.").unwrap();
+        let optimized = bf_str.optimize(&Pipeline::level(3), &Config::default());
+        let preset = Op::Preset {
+            output: b"A".to_vec(),
+            runs: vec![Run {
+                start: 1,
+                cells: vec![65],
+            }],
+            // the `>` is still pending as an offset of `,` and `.`
+            ptr: 0,
+        };
+        assert_eq!(optimized[0].operator, preset);
+        let rest: Vec<Op> = optimized[1..]
+            .iter()
+            .map(|op| op.operator.clone())
+            .collect();
+        assert_eq!(rest, [Op::Acp, Op::Out, Op::Shf]);
+        let mut ret = Vec::new();
+        bf_str
+            ._interpret(&Pipeline::level(3), &Config::default(), &b"B"[..], &mut ret)
+            .unwrap();
+        assert_eq!(ret, b"AB");
+        assert_eq!(gcc_run(&bf_str, 3, &Config::default(), b"B")?.stdout, b"AB");
+
+        // only the cells that were set are kept, split at long runs of zeros
+        let source = format!("+{}++>>>+++.,", ">".repeat(20));
+        let optimized = BfStr::parse(&source)
+            .unwrap()
+            .optimize(&Pipeline::level(3), &Config::default());
+        let runs = vec![
+            Run {
+                start: 0,
+                cells: vec![1],
+            },
+            Run {
+                start: 20,
+                cells: vec![2, 0, 0, 3],
+            },
+        ];
+        assert!(matches!(
+            &optimized[0].operator,
+            Op::Preset { runs: folded, .. } if *folded == runs
+        ));
+
+        // a cell far out doesn't bring the zeros before it along
+        let bf_str = BfStr::parse(&(">".repeat(500_000) + "+,.")).unwrap();
+        let mut c_code = Vec::new();
+        bf_str._cc(
+            &mut c_code,
+            &Pipeline::level(3),
+            &Config::default(),
+            &CcOptions::default(),
+        )?;
+        assert!(c_code.len() < 10_000);
+        assert_eq!(gcc_run(&bf_str, 3, &Config::default(), b"B")?.stdout, b"B");
+
+        // what ran at compile time still counts towards the step limit
+        let bf_str = BfStr::parse("++++++++[>++++[>++<-]<-]>>+.").unwrap();
+        for (limit, ok) in [(10, false), (1000, true)] {
+            let config = Config {
+                step_limit: Some(limit),
+                ..Config::default()
+            };
+            let mut ret = Vec::new();
+            let result = bf_str._interpret(&Pipeline::level(3), &config, io::empty(), &mut ret);
+            match result {
+                Ok(()) => assert!(ok && ret == b"A"),
+                Err(err) => assert!(!ok && matches!(err, InterpretError::StepLimit { .. })),
+            }
+        }
+
+        // anything that never ends or fails is left to happen at runtime
+        let trap = Config {
+            overflow: Overflow::Trap,
+            ..Config::default()
+        };
+        for (source, config, preset, rest) in [
+            ("+>+[]", Config::default(), true, 2),
+            ("+<", Config::default(), true, 1),
+            ("+--", trap, false, 1),
+        ] {
+            let optimized = BfStr::parse(source)
+                .unwrap()
+                .optimize(&Pipeline::level(3), &config);
+            let folded = matches!(optimized[0].operator, Op::Preset { .. });
+            assert_eq!((folded, optimized.len()), (preset, rest + preset as usize));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_passes() {
+        for pass in Pass::ALL {
+            assert_eq!(pass.to_string().parse::<Pass>(), Ok(pass));
+        }
+        assert!("mul".parse::<Pass>().is_err());
+        assert!(Pipeline::level(0).passes.is_empty());
+        assert_eq!(Pipeline::level(3).passes, Pass::ALL);
+
+        // passes can run in any order, e.g. the offsets first
+        let bf_str = BfStr::parse("+++>++[-<+>]<.").unwrap();
+        let pipeline = Pipeline {
+            passes: vec![Pass::Offset, Pass::MulLoop],
+            ..Pipeline::default()
+        };
+        let optimized = bf_str.optimize(&pipeline, &Config::default());
+        assert!(optimized.iter().any(|op| matches!(
+            op.operator,
+            Op::MulAdd {
+                offset: -1,
+                factor: 1
+            }
+        )));
+        let mut ret = Vec::new();
+        bf_str
+            ._interpret(&pipeline, &Config::default(), io::empty(), &mut ret)
+            .unwrap();
+        assert_eq!(ret, [5]);
+    }
+
+    #[test]
+    fn test_dump_ir() {
+        let bf_str = BfStr::parse("+[>.<-]\n>,").unwrap();
+        let text = bf_str.dump_ir(&Pipeline::default(), &Config::default(), IrFormat::Text);
+        let lines: Vec<String> = text
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "0 upd [+0] +1 ; 1:1",
+                "1 jpf 6 ; 1:2",
+                "2 shf +1 ; 1:3",
+                "3 out [+0] x1 ; 1:4",
+                "4 shf -1 ; 1:5",
+                "5 upd [+0] -1 ; 1:6",
+                "6 jpb 1 ; 1:7",
+                "7 shf +1 ; 2:1",
+                "8 acp [+0] ; 2:2",
+            ]
+        );
+
+        let bf_str = BfStr::parse("+[-]>,").unwrap();
+        let json = bf_str.dump_ir(&Pipeline::level(2), &Config::default(), IrFormat::Json);
+        assert_eq!(
+            json,
+            concat!(
+                "[\n",
+                r#"  {"index": 0, "op": "upd", "offset": 0, "delta": 1, "#,
+                r#""span": {"start": 0, "end": 1, "line": 1, "column": 1}},"#,
+                "\n",
+                r#"  {"index": 1, "op": "set", "offset": 0, "value": 0, "#,
+                r#""span": {"start": 1, "end": 4, "line": 1, "column": 2}},"#,
+                "\n",
+                r#"  {"index": 2, "op": "acp", "offset": 1, "#,
+                r#""span": {"start": 5, "end": 6, "line": 1, "column": 6}},"#,
+                "\n",
+                r#"  {"index": 3, "op": "shf", "delta": 1, "#,
+                r#""span": {"start": 4, "end": 5, "line": 1, "column": 5}}"#,
+                "\n]\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_llvm() -> io::Result<()> {
+        check_native(lli_run)
+    }
+
+    /// Check a native backend, where `run` compiles and runs a program or
+    /// gives `None` without the tools it needs, against the samples and
+    /// every config option. Without the tools the check passes, unless
+    /// `BFTER_REQUIRE_TOOLCHAINS` is set, as it should be wherever they're
+    /// all installed.
+    fn check_native(
+        run: impl Fn(&BfStr, u8, &Config, &[u8]) -> io::Result<Option<Output>>,
+    ) -> io::Result<()> {
+        for level in [0, 3] {
+            for (path, input, output) in &samples() {
+                let bf_str = BfStr::from_file(path)?;
+                let Some(ret) = run(&bf_str, level, &Config::default(), input.as_bytes())? else {
+                    assert!(
+                        std::env::var_os("BFTER_REQUIRE_TOOLCHAINS").is_none(),
+                        "the toolchain for this backend isn't installed"
+                    );
+                    return Ok(());
+                };
+                assert_eq!(ret.stdout, output.as_bytes());
+            }
+        }
+
+        let wide = BfStr::parse(concat!(
+            "++++++++++++++++[>++++++++++++++++<-]>",
+            "[[-]>++++++++[<++++++++>-]<+.[-]]",
+        ))
+        .unwrap();
+        let overflow = BfStr::parse("#\n->++++++[<+++++++++++>-]<.").unwrap();
+        let read = BfStr::parse(",.").unwrap();
+        let scan = BfStr::parse(">+>+>+[<]>.").unwrap();
+        let underflow = BfStr::parse("+[<]").unwrap();
+        let shift_underflow = BfStr::parse("+<").unwrap();
+        let config = Config::default();
+        for (bf_str, config, output, error) in [
+            (&wide, config.clone(), "", None),
+            (
+                &overflow,
+                Config {
+                    overflow: Overflow::Saturate,
+                    ..config.clone()
+                },
+                "B",
+                None,
+            ),
+            (
+                &overflow,
+                Config {
+                    overflow: Overflow::Trap,
+                    ..config.clone()
+                },
+                "",
+                Some("error: cell overflow at line 2, column 1"),
+            ),
+            (
+                &read,
+                Config {
+                    eof: Eof::MinusOne,
+                    ..config.clone()
+                },
+                "\u{ff}",
+                None,
+            ),
+            (
+                &read,
+                Config {
+                    eof: Eof::Error,
+                    ..config.clone()
+                },
+                "",
+                Some("error: unexpected end of input at line 1, column 1"),
+            ),
+            (&scan, config.clone(), "\u{1}", None),
+            (
+                &underflow,
+                config.clone(),
+                "",
+                Some("error: tape underflow at line 1"),
+            ),
+            (
+                &shift_underflow,
+                config.clone(),
+                "",
+                Some("error: tape underflow at line 1, column 2"),
+            ),
+        ]
+        .into_iter()
+        .chain(
+            [CellBits::U16, CellBits::U32, CellBits::U64]
+                .into_iter()
+                .flat_map(|cell_bits| {
+                    // the checked paths too, which work in 64 bits for every width
+                    [Overflow::Wrap, Overflow::Saturate, Overflow::Trap].map(|overflow| {
+                        let config = Config {
+                            cell_bits,
+                            overflow,
+                            ..Config::default()
+                        };
+                        (&wide, config, "A", None)
+                    })
+                }),
+        ) {
+            for level in [0, 3] {
+                let ret = run(bf_str, level, &config, b"")?.unwrap();
+                let expected: Vec<u8> = output.chars().map(|c| c as u8).collect();
+                assert_eq!(ret.stdout, expected, "{:?} at level {}", config, level);
+                match error {
+                    Some(error) => {
+                        assert_eq!(ret.status.code(), Some(3));
+                        assert!(String::from_utf8_lossy(&ret.stderr).contains(error));
+                    }
+                    None => assert!(ret.status.success()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_asm() -> io::Result<()> {
+        check_native(asm_run)
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_elf() -> io::Result<()> {
+        check_native(|bf_str, level, config, input| {
+            let temp_dir = tempfile::tempdir()?;
+            let exec = temp_dir.path().join("prog");
+            bf_str.elf(&exec, &Pipeline::level(level), config)?;
+            run_with_input(&mut Command::new(&exec), input).map(Some)
+        })
+    }
+
+    /// The ids of the sections of a binary module, checking that they're
+    /// in order and fill it exactly.
+    fn wasm_sections(module: &[u8]) -> Vec<u8> {
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let mut ids = Vec::new();
+        let mut at = 8;
+        while at < module.len() {
+            let id = module[at];
+            assert!(ids.last().is_none_or(|last| *last < id));
+            ids.push(id);
+            at += 1;
+            let (mut size, mut shift) = (0, 0);
+            loop {
+                let byte = module[at];
+                at += 1;
+                size |= ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            at += size;
+        }
+        assert_eq!(at, module.len());
+        ids
+    }
+
+    #[test]
+    fn test_wasm() -> io::Result<()> {
+        for level in [0, 3] {
+            for (path, _, _) in &samples() {
+                let bf_str = BfStr::from_file(path)?;
+                let mut wat = Vec::new();
+                bf_str._wasm(&mut wat, &Pipeline::level(level), &Config::default(), false)?;
+                let wat = String::from_utf8(wat).unwrap();
+                assert!(wat.starts_with("(module\n"));
+                assert!(wat.contains("(import \"wasi_snapshot_preview1\" \"fd_read\""));
+                assert!(wat.contains("(import \"wasi_snapshot_preview1\" \"fd_write\""));
+                assert!(wat.contains("(func $_start (export \"_start\")"));
+
+                let mut module = Vec::new();
+                bf_str._wasm(
+                    &mut module,
+                    &Pipeline::level(level),
+                    &Config::default(),
+                    true,
+                )?;
+                // type, import, function, memory, global, export, code, data
+                assert_eq!(wasm_sections(&module), [1, 2, 3, 5, 6, 7, 10, 11]);
+                wasmparser::Validator::new().validate_all(&module).unwrap();
+            }
+        }
+
+        // every cell width and policy makes a valid module, even where
+        // `wasmtime` isn't around to run it
+        let bf_str = BfStr::parse(">+>+[<]>[->++<]+[>]<<-,.<[-]++[>]").unwrap();
+        for cell_bits in [CellBits::U8, CellBits::U16, CellBits::U32, CellBits::U64] {
+            for overflow in [Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+                for eof in [Eof::Unchanged, Eof::Zero, Eof::MinusOne, Eof::Error] {
+                    let config = Config {
+                        cell_bits,
+                        overflow,
+                        eof,
+                        ..Config::default()
+                    };
+                    for level in [0, 3] {
+                        let mut module = Vec::new();
+                        bf_str._wasm(&mut module, &Pipeline::level(level), &config, true)?;
+                        wasmparser::Validator::new().validate_all(&module).unwrap();
+                    }
+                }
+            }
+        }
+
+        // a preset past the end of the tape is refused, not written over
+        // the constants after it
+        let bf_str = BfStr::parse(&(">".repeat(1 << 20) + "+,")).unwrap();
+        let err = bf_str
+            ._wasm(io::sink(), &Pipeline::level(3), &Config::default(), true)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        check_native(wasmtime_run)
+    }
+
+    #[test]
+    fn test_rust() -> io::Result<()> {
+        check_native(rustc_run)
+    }
 }
@@ -0,0 +1,427 @@
+//! The optimizer, as a list of named passes over the ops.
+
+use super::{cell::Cell, dump::disassemble, CellBits, Config, Machine, Op, Operation, Run, Span};
+use std::{collections::BTreeMap, fmt, io, str::FromStr};
+
+/// One rewrite of the op stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    /// `[-]` into `Set(0)`.
+    ClearLoop,
+    /// Copy and multiply loops like `[->+>++<<]` into `MulAdd`s and `Set(0)`.
+    MulLoop,
+    /// `[>]`, `[<]` and `[>>>]` into `Scan`.
+    ScanLoop,
+    /// Pointer moves into the offsets of the ops after them.
+    Offset,
+    /// Remove loops that can never run.
+    DeadLoop,
+    /// Run the input-free start of the program at compile time.
+    ConstFold,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 6] = [
+        Pass::ClearLoop,
+        Pass::MulLoop,
+        Pass::ScanLoop,
+        Pass::Offset,
+        Pass::DeadLoop,
+        Pass::ConstFold,
+    ];
+
+    /// The passes of an optimization level, in the order they run: none at
+    /// 0, the cheap loop rewrites at 1, everything that doesn't run the
+    /// program at 2, and everything at 3 and above.
+    pub fn level(level: u8) -> Vec<Pass> {
+        match level {
+            0 => vec![],
+            1 => vec![Pass::ClearLoop, Pass::ScanLoop, Pass::Offset],
+            2 => Pass::ALL[..5].to_vec(),
+            _ => Pass::ALL.to_vec(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Pass::ClearLoop => "clear-loop",
+            Pass::MulLoop => "mul-loop",
+            Pass::ScanLoop => "scan-loop",
+            Pass::Offset => "offset",
+            Pass::DeadLoop => "dead-loop",
+            Pass::ConstFold => "const-fold",
+        }
+    }
+
+    /// Run the pass over `ops`, which must have their jumps linked.
+    fn run(self, ops: Vec<Operation>, config: &Config, report: &mut OptReport) -> Vec<Operation> {
+        match self {
+            /*
+            i know where it jumps to, so i could get the loop sequence
+            then i check the pattern:
+                if the loop body only has `+-<>`, ends where it starts, and takes exactly 1 from
+                the start cell per round, it runs `cell` rounds and adds `factor * cell` to every
+                other cell it touches, e.g. [->+>++>>---<<<<] or [-] with no other cell at all
+                then the whole loop can be replaced with: MulAdd for each cell and Set(0)
+                if the loop body is a single `>`/`<` run, it's a scan for the next zero cell,
+                e.g. [>] or [<<<], which can be replaced with: Scan(stride)
+             */
+            Pass::ClearLoop => lower_loops(ops, |body, span| match balanced_loop(body) {
+                Some(targets) if targets.is_empty() => Some(lower_balanced_loop(&targets, span)),
+                _ => None,
+            }),
+            Pass::MulLoop => lower_loops(ops, |body, span| match balanced_loop(body) {
+                Some(targets) if !targets.is_empty() => Some(lower_balanced_loop(&targets, span)),
+                _ => None,
+            }),
+            Pass::ScanLoop => lower_loops(ops, |body, span| match body {
+                [Operation {
+                    operator: Op::Shf,
+                    operand: stride,
+                    ..
+                }] => Some(vec![Operation::new(Op::Scan { stride: *stride }, 0, span)]),
+                _ => None,
+            }),
+            Pass::Offset => fold_offsets(ops),
+            Pass::DeadLoop => {
+                let (ops, removed) = remove_dead_loops(ops);
+                report.dead_loops += removed;
+                ops
+            }
+            Pass::ConstFold => match config.cell_bits {
+                CellBits::U8 => const_fold::<u8>(ops, config),
+                CellBits::U16 => const_fold::<u16>(ops, config),
+                CellBits::U32 => const_fold::<u32>(ops, config),
+                CellBits::U64 => const_fold::<u64>(ops, config),
+            },
+        }
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL
+            .into_iter()
+            .find(|pass| pass.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Pass::ALL.iter().map(|pass| pass.name()).collect();
+                format!("unknown pass `{}`, expect one of {}", s, names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Which passes to run, and whether to show what each of them did.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    pub passes: Vec<Pass>,
+    /// Print the ops to stderr after every pass.
+    pub print_after_all: bool,
+}
+
+impl Pipeline {
+    pub fn level(level: u8) -> Self {
+        Pipeline {
+            passes: Pass::level(level),
+            print_after_all: false,
+        }
+    }
+
+    /// Run every pass over `ops` in order.
+    pub(super) fn run(
+        &self,
+        mut ops: Vec<Operation>,
+        config: &Config,
+    ) -> (Vec<Operation>, OptReport) {
+        let mut report = OptReport::default();
+        for pass in &self.passes {
+            ops = pass.run(ops, config, &mut report);
+            link_jumps(&mut ops);
+            if self.print_after_all {
+                eprintln!("; after {}", pass);
+                eprint!("{}", disassemble(&ops));
+            }
+        }
+        (ops, report)
+    }
+}
+
+/// What the passes did, for the user to see.
+#[derive(Debug, Clone, Default)]
+pub struct OptReport {
+    /// Loops removed because they could never run.
+    pub dead_loops: usize,
+}
+
+/// Replace every loop, innermost last, whose body `lower` gives a
+/// replacement for.
+fn lower_loops(
+    ops: Vec<Operation>,
+    lower: impl Fn(&[Operation], Span) -> Option<Vec<Operation>>,
+) -> Vec<Operation> {
+    let mut lowered: Vec<Operation> = Vec::with_capacity(ops.len());
+    let mut pos: usize = 0;
+    while pos < ops.len() {
+        let op = &ops[pos];
+        if op.operator == Op::Jpf {
+            let jpb = op.operand as usize - 1;
+            // the whole loop, brackets included
+            let span = op.span.merge(ops[jpb].span);
+            if let Some(replacement) = lower(&ops[pos + 1..jpb], span) {
+                lowered.extend(replacement);
+                pos = jpb + 1;
+                continue;
+            }
+        }
+        lowered.push(op.clone());
+        pos += 1;
+    }
+    lowered
+}
+
+/// The `(offset, factor)` of every cell a loop body adds `factor` to per
+/// round, if the body only has `Upd` and `Shf`, ends at the cell it starts
+/// at, and takes exactly 1 from that cell per round.
+fn balanced_loop(body: &[Operation]) -> Option<Vec<(isize, isize)>> {
+    let mut offset = 0;
+    let mut deltas: BTreeMap<isize, isize> = BTreeMap::new();
+    for op in body {
+        match op.operator {
+            Op::Upd => *deltas.entry(offset + op.offset).or_default() += op.operand,
+            Op::Shf => offset += op.operand,
+            _ => return None,
+        }
+    }
+    if offset != 0 || deltas.remove(&0) != Some(-1) {
+        return None;
+    }
+    Some(
+        deltas
+            .into_iter()
+            .filter(|(_, factor)| *factor != 0)
+            .collect(),
+    )
+}
+
+/// Ops adding `factor * cell` to `cell[offset]` for every target, then
+/// clearing the cell.
+fn lower_balanced_loop(targets: &[(isize, isize)], span: Span) -> Vec<Operation> {
+    let mut lowered: Vec<Operation> = targets
+        .iter()
+        .map(|&(offset, factor)| Operation::new(Op::MulAdd { offset, factor }, 0, span))
+        .collect();
+    lowered.push(Operation::new(Op::Set, 0, span));
+    lowered
+}
+
+/// Fold pointer moves into the `offset` of the `Upd`, `Set`, `Out` and `Acp`
+/// ops after them, so `>+>+<<` needs no `Shf` at all. The pointer only really
+/// moves right before a loop boundary, a scan or a multiply-add, and at the
+/// end of the program.
+fn fold_offsets(ops: Vec<Operation>) -> Vec<Operation> {
+    let mut folded: Vec<Operation> = Vec::with_capacity(ops.len());
+    // the net move not done yet, and the span of the `Shf`s it came from
+    let mut pending: Option<(isize, Span)> = None;
+    for mut op in ops {
+        match op.operator {
+            Op::Shf => {
+                pending = Some(match pending {
+                    Some((delta, span)) => (delta + op.operand, span.merge(op.span)),
+                    None => (op.operand, op.span),
+                });
+                continue;
+            }
+            Op::Upd | Op::Set | Op::Out | Op::Acp => {
+                op.offset += pending.map_or(0, |(delta, _)| delta);
+            }
+            _ => {
+                if let Some((delta, span)) = pending.take() {
+                    if delta != 0 {
+                        folded.push(Operation::new(Op::Shf, delta, span));
+                    }
+                }
+            }
+        }
+        folded.push(op);
+    }
+    if let Some((delta, span)) = pending {
+        if delta != 0 {
+            folded.push(Operation::new(Op::Shf, delta, span));
+        }
+    }
+    folded
+}
+
+/// Remove loops that can never run because the cell is known to be zero at
+/// the `[`, e.g. a comment loop at the start of the program or right after
+/// another loop. Returns the remaining ops and how many loops were removed.
+fn remove_dead_loops(ops: Vec<Operation>) -> (Vec<Operation>, usize) {
+    // whether the cell at each offset from the pointer is known to be zero,
+    // and whether all other cells are too, which is only true until the
+    // first loop or scan
+    let mut zeros: BTreeMap<isize, bool> = BTreeMap::new();
+    let mut rest_zero = true;
+    let mut kept: Vec<Operation> = Vec::with_capacity(ops.len());
+    let mut removed = 0;
+    let mut ops = ops.into_iter();
+    while let Some(op) = ops.next() {
+        match op.operator {
+            Op::Jpf if zeros.get(&0).copied().unwrap_or(rest_zero) => {
+                let mut depth = 1;
+                for op in ops.by_ref() {
+                    match op.operator {
+                        Op::Jpf => depth += 1,
+                        Op::Jpb => depth -= 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                removed += 1;
+                continue;
+            }
+            Op::Upd | Op::Acp | Op::MulAdd { .. } => {
+                let offset = match op.operator {
+                    Op::MulAdd { offset, .. } => offset,
+                    _ => op.offset,
+                };
+                zeros.insert(offset, false);
+            }
+            Op::Set => {
+                zeros.insert(op.offset, op.operand == 0);
+            }
+            Op::Shf => {
+                zeros = zeros
+                    .into_iter()
+                    .map(|(offset, zero)| (offset - op.operand, zero))
+                    .collect();
+            }
+            Op::Out => (),
+            // a loop body may run with anything on the tape, and only the
+            // cell a loop or scan stops at is known to be zero
+            Op::Jpf | Op::Preset { .. } => {
+                zeros.clear();
+                rest_zero = false;
+            }
+            Op::Jpb | Op::Scan { .. } => {
+                zeros = BTreeMap::from([(0, true)]);
+                rest_zero = false;
+            }
+        }
+        kept.push(op);
+    }
+    (kept, removed)
+}
+
+/// How many ops `const_fold` may run at compile time before it gives up.
+const CONST_FOLD_BUDGET: u64 = 1 << 20;
+
+/// Run the program at compile time up to the first op that reads input, and
+/// replace all that with one `Preset` op holding its output and the tape it
+/// leaves behind. A program that never reads input becomes just that op.
+fn const_fold<C: Cell>(ops: Vec<Operation>, config: &Config) -> Vec<Operation> {
+    // never more than the program may take at runtime either, since the
+    // steps run here are still counted there
+    let budget = config
+        .step_limit
+        .map_or(CONST_FOLD_BUDGET, |limit| limit.min(CONST_FOLD_BUDGET));
+    let config = Config {
+        step_limit: Some(budget),
+        ..config.clone()
+    };
+    let mut machine: Machine<C> = Machine::new(1);
+    let mut output: Vec<u8> = Vec::new();
+    // one op or whole loop at a time, so the rest always starts at a point
+    // the code after it can jump around; the first one that reads input,
+    // fails or runs out of steps is left to happen at runtime
+    while machine.pos < ops.len() {
+        let end = match ops[machine.pos].operator {
+            Op::Jpf => ops[machine.pos].operand as usize,
+            _ => machine.pos + 1,
+        };
+        if ops[machine.pos..end]
+            .iter()
+            .any(|op| op.operator == Op::Acp)
+        {
+            break;
+        }
+        let (saved, written) = (machine.clone(), output.len());
+        if machine
+            .run(&ops, end, &config, &mut io::empty(), &mut output)
+            .is_err()
+        {
+            machine = saved;
+            output.truncate(written);
+            break;
+        }
+    }
+    if machine.pos == 0 {
+        return ops;
+    }
+    let cells: Vec<u64> = machine
+        .tape
+        .iter()
+        .map(|cell| cell.to_i128() as u64)
+        .collect();
+    let span = ops[0].span.merge(ops[machine.pos - 1].span);
+    let preset = Op::Preset {
+        output,
+        runs: preset_runs(&cells),
+        ptr: machine.prt,
+    };
+    let mut folded = vec![Operation::new(preset, machine.steps as isize, span)];
+    folded.extend(ops.into_iter().skip(machine.pos));
+    link_jumps(&mut folded);
+    folded
+}
+
+/// How many zero cells in a row a preset run may hold before it's split in
+/// two, so a far-off cell doesn't drag the whole tape before it along.
+const PRESET_GAP: usize = 16;
+
+/// The non-zero cells of `cells` as runs, with short stretches of zeros kept
+/// inside a run.
+fn preset_runs(cells: &[u64]) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+    for (idx, &value) in cells.iter().enumerate() {
+        if value == 0 {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if idx - run.end() <= PRESET_GAP => {
+                run.cells.resize(idx - run.start, 0);
+                run.cells.push(value);
+            }
+            _ => runs.push(Run {
+                start: idx,
+                cells: vec![value],
+            }),
+        }
+    }
+    runs
+}
+
+/// Point every `Jpf` just past its `Jpb` and every `Jpb` just past its `Jpf`,
+/// the same as the parser does, after a pass has moved ops around.
+fn link_jumps(ops: &mut [Operation]) {
+    let mut jmp_stack: Vec<usize> = Vec::new();
+    for idx in 0..ops.len() {
+        match ops[idx].operator {
+            Op::Jpf => jmp_stack.push(idx),
+            Op::Jpb => {
+                let open = jmp_stack.pop().expect("unbalanced jump after optimization");
+                ops[open].operand = (idx + 1) as isize;
+                ops[idx].operand = (open + 1) as isize;
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! The Rust backend: a self-contained `main.rs` that builds with plain
+//! `rustc`. The ops become calls on a small `Tape` runtime at the top of the
+//! file, loops become `while` blocks, and output goes through a buffered
+//! `stdout`.
+
+use super::{BfStr, Config, Op, OptReport, Pipeline};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+static FILE_HEAD: &str = concat!(
+    "#![allow(dead_code)]\n",
+    "\n",
+    "use std::io::{self, BufWriter, Read, StdoutLock, Write};\n",
+    "use std::process;\n",
+    "\n",
+    "#[derive(PartialEq)]\n",
+    "enum Overflow {\n",
+    "    Wrap,\n",
+    "    Saturate,\n",
+    "    Trap,\n",
+    "}\n",
+    "\n",
+    "#[derive(PartialEq)]\n",
+    "enum Eof {\n",
+    "    Unchanged,\n",
+    "    Zero,\n",
+    "    MinusOne,\n",
+    "    Error,\n",
+    "}\n",
+    "\n",
+);
+
+static TAPE_HEAD: &str = concat!(
+    "\n",
+    "struct Tape {\n",
+    "    cells: Vec<Cell>,\n",
+    "    ptr: usize,\n",
+    "    out: BufWriter<StdoutLock<'static>>,\n",
+    "}\n",
+    "\n",
+    "impl Tape {\n",
+    "    fn new() -> Self {\n",
+    "        Tape {\n",
+    "            cells: vec![0; 1024],\n",
+    "            ptr: 0,\n",
+    "            out: BufWriter::new(io::stdout().lock()),\n",
+    "        }\n",
+    "    }\n",
+    "\n",
+    "    fn fail(&mut self, what: &str, loc: &str) -> ! {\n",
+    "        let _ = self.out.flush();\n",
+    "        eprintln!(\"error: {} at {} (tape pointer {})\", what, loc, self.ptr);\n",
+    "        process::exit(3);\n",
+    "    }\n",
+    "\n",
+    "    fn io_fail(&mut self, err: io::Error) -> ! {\n",
+    "        eprintln!(\"error: {}\", err);\n",
+    "        process::exit(3);\n",
+    "    }\n",
+    "\n",
+    "    /// The index of the cell `offset` away from the pointer, growing the\n",
+    "    /// tape to hold it.\n",
+    "    fn at(&mut self, offset: isize, loc: &str) -> usize {\n",
+    "        let Some(idx) = self.ptr.checked_add_signed(offset) else {\n",
+    "            self.fail(\"tape underflow\", loc);\n",
+    "        };\n",
+    "        if idx >= self.cells.len() {\n",
+    "            let len = (idx + 1).max(self.cells.len() * 2);\n",
+    "            self.cells.resize(len, 0);\n",
+    "        }\n",
+    "        idx\n",
+    "    }\n",
+    "\n",
+    "    fn cur(&self) -> Cell {\n",
+    "        self.cells[self.ptr]\n",
+    "    }\n",
+    "\n",
+    "    fn shift(&mut self, delta: isize, loc: &str) {\n",
+    "        self.ptr = self.at(delta, loc);\n",
+    "    }\n",
+    "\n",
+    "    /// Add `delta` to the cell at `idx` under the overflow policy.\n",
+    "    fn add(&mut self, idx: usize, delta: i128, loc: &str) {\n",
+    "        let value = self.cells[idx] as i128 + delta;\n",
+    "        if OVERFLOW == Overflow::Wrap || (0..=Cell::MAX as i128).contains(&value) {\n",
+    "            // keeps the low bits, which is wrapping\n",
+    "            self.cells[idx] = value as Cell;\n",
+    "        } else if OVERFLOW == Overflow::Saturate {\n",
+    "            self.cells[idx] = if value < 0 { 0 } else { Cell::MAX };\n",
+    "        } else {\n",
+    "            self.fail(\"cell overflow\", loc);\n",
+    "        }\n",
+    "    }\n",
+    "\n",
+    "    fn update(&mut self, offset: isize, delta: i128, loc: &str) {\n",
+    "        let idx = self.at(offset, loc);\n",
+    "        self.add(idx, delta, loc);\n",
+    "    }\n",
+    "\n",
+    "    fn set(&mut self, offset: isize, value: Cell, loc: &str) {\n",
+    "        let idx = self.at(offset, loc);\n",
+    "        self.cells[idx] = value;\n",
+    "    }\n",
+    "\n",
+    "    fn mul_add(&mut self, offset: isize, factor: i128, loc: &str) {\n",
+    "        let src = self.cur();\n",
+    "        if src != 0 {\n",
+    "            let idx = self.at(offset, loc);\n",
+    "            self.add(idx, src as i128 * factor, loc);\n",
+    "        }\n",
+    "    }\n",
+    "\n",
+    "    fn scan(&mut self, stride: isize, loc: &str) {\n",
+    "        match stride {\n",
+    "            1 => match self.cells[self.ptr..].iter().position(|&cell| cell == 0) {\n",
+    "                Some(found) => self.ptr += found,\n",
+    "                // the cells past the end are all zero\n",
+    "                None => self.shift((self.cells.len() - self.ptr) as isize, loc),\n",
+    "            },\n",
+    "            -1 => match self.cells[..=self.ptr].iter().rposition(|&cell| cell == 0) {\n",
+    "                Some(found) => self.ptr = found,\n",
+    "                None => self.fail(\"tape underflow\", loc),\n",
+    "            },\n",
+    "            _ => {\n",
+    "                while self.cur() != 0 {\n",
+    "                    self.shift(stride, loc);\n",
+    "                }\n",
+    "            }\n",
+    "        }\n",
+    "    }\n",
+    "\n",
+    "    fn input(&mut self, offset: isize, loc: &str) {\n",
+    "        let idx = self.at(offset, loc);\n",
+    "        if let Err(err) = self.out.flush() {\n",
+    "            self.io_fail(err);\n",
+    "        }\n",
+    "        let mut byte = [0];\n",
+    "        match io::stdin().read(&mut byte) {\n",
+    "            Ok(1) => self.cells[idx] = byte[0] as Cell,\n",
+    "            Ok(_) => match EOF {\n",
+    "                Eof::Unchanged => (),\n",
+    "                Eof::Zero => self.cells[idx] = 0,\n",
+    "                Eof::MinusOne => self.cells[idx] = Cell::MAX,\n",
+    "                Eof::Error => self.fail(\"unexpected end of input\", loc),\n",
+    "            },\n",
+    "            Err(err) => self.io_fail(err),\n",
+    "        }\n",
+    "    }\n",
+    "\n",
+    "    fn output(&mut self, offset: isize, count: usize, loc: &str) {\n",
+    "        let idx = self.at(offset, loc);\n",
+    "        let byte = self.cells[idx] as u8;\n",
+    "        self.write(&[byte].repeat(count));\n",
+    "    }\n",
+    "\n",
+    "    fn write(&mut self, bytes: &[u8]) {\n",
+    "        if let Err(err) = self.out.write_all(bytes) {\n",
+    "            self.io_fail(err);\n",
+    "        }\n",
+    "    }\n",
+    "\n",
+    "    fn preset(&mut self, start: usize, cells: &[Cell]) {\n",
+    "        let end = start + cells.len();\n",
+    "        if self.cells.len() < end {\n",
+    "            self.cells.resize(end, 0);\n",
+    "        }\n",
+    "        self.cells[start..end].copy_from_slice(cells);\n",
+    "    }\n",
+    "\n",
+    "    fn finish(&mut self) {\n",
+    "        if let Err(err) = self.out.flush() {\n",
+    "            self.io_fail(err);\n",
+    "        }\n",
+    "    }\n",
+    "}\n",
+    "\n",
+    "fn main() {\n",
+    "    let mut t = Tape::new();\n",
+);
+
+static MAIN_TAIL: &str = concat!("    t.finish();\n", "}\n");
+
+/// `bytes` as a Rust byte string literal.
+fn rust_bytes(bytes: &[u8]) -> String {
+    let mut literal = String::from("b\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => literal.push_str(&format!("\\{}", byte as char)),
+            b'\n' => literal.push_str("\\n"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+impl BfStr {
+    fn _rust(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let (ops, report) = self.optimize_report(pipeline, config);
+        write!(write, "{}", FILE_HEAD)?;
+        writeln!(write, "type Cell = u{};", config.cell_bits.bits())?;
+        let overflow = config.overflow.to_string();
+        let overflow = overflow[..1].to_uppercase() + &overflow[1..];
+        writeln!(write, "const OVERFLOW: Overflow = Overflow::{};", overflow)?;
+        let eof: String = config
+            .eof
+            .to_string()
+            .split('-')
+            .map(|word| word[..1].to_uppercase() + &word[1..])
+            .collect();
+        writeln!(write, "const EOF: Eof = Eof::{};", eof)?;
+        write!(write, "{}", TAPE_HEAD)?;
+
+        let mut depth = 1;
+        for (idx, op) in ops.iter().enumerate() {
+            let loc = format!("\"line {}, column {}\"", op.span.line, op.span.column);
+            let line = match op.operator {
+                Op::Upd => format!("t.update({}, {}, {});", op.offset, op.operand, loc),
+                Op::Shf => format!("t.shift({}, {});", op.operand, loc),
+                Op::Scan { stride } => format!("t.scan({}, {});", stride, loc),
+                Op::Acp => format!("t.input({}, {});", op.offset, loc),
+                Op::Out => format!("t.output({}, {}, {});", op.offset, op.operand, loc),
+                Op::Jpf => {
+                    depth += 1;
+                    String::from("while t.cur() != 0 {")
+                }
+                Op::Jpb => {
+                    depth -= 1;
+                    String::from("}")
+                }
+                Op::Set => format!("t.set({}, {}, {});", op.offset, op.operand, loc),
+                Op::MulAdd { offset, factor } => {
+                    format!("t.mul_add({}, {}, {});", offset, factor, loc)
+                }
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => {
+                    let mut lines = Vec::new();
+                    if !output.is_empty() {
+                        lines.push(format!("t.write({});", rust_bytes(output)));
+                    }
+                    // nobody can look at the tape once the program ends
+                    if idx + 1 < ops.len() {
+                        for run in runs {
+                            let values: Vec<String> =
+                                run.cells.iter().map(u64::to_string).collect();
+                            lines.push(format!(
+                                "t.preset({}, &[{}]);",
+                                run.start,
+                                values.join(", ")
+                            ));
+                        }
+                        if ptr > 0 {
+                            lines.push(format!("t.shift({}, \"\");", ptr));
+                        }
+                    }
+                    lines.join("\n    ")
+                }
+            };
+            let indent = match op.operator {
+                Op::Jpf => depth - 1,
+                _ => depth,
+            };
+            writeln!(write, "{:indent$}{}", "", line, indent = indent * 4)?;
+        }
+        write!(write, "{}", MAIN_TAIL)?;
+        Ok(report)
+    }
+
+    pub fn rust(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+    ) -> io::Result<OptReport> {
+        let file = File::create(save_path)?;
+        self._rust(file, pipeline, config)
+    }
+}
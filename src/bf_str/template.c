@@ -1,6 +1,6 @@
 #include <assert.h>
-#include <inttypes.h>
 #include <stdbool.h>
+#include <inttypes.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -18,52 +18,133 @@
         (da)->items[(da)->len++] = (item);                                          \
     } while (0)                                                                     \
 
+typedef uint8_t cell_t;
+#define OVERFLOW_WRAP
+#define EOF_UNCHANGED
+
 typedef struct {
-    uint8_t* items;
+    cell_t* items;
     size_t len;
     size_t cap;
     size_t ptr;
 } Tape;
 
-uint8_t tape_curr(Tape* tape) {
+cell_t tape_curr(Tape* tape) {
     return tape->items[tape->ptr];
 }
 
-void tape_assign(Tape* tape, uint8_t u8) {
-    tape->items[tape->ptr] = u8;
+void tape_assign(Tape* tape, cell_t value) {
+    tape->items[tape->ptr] = value;
+}
+
+void tape_fail(Tape* tape, const char* what, const char* loc) {
+    fprintf(stderr, "error: %s at %s (tape pointer %zu)\n", what, loc, tape->ptr);
+    exit(3);
 }
 
-void tape_shift(Tape* tape, int64_t delta) {
-    int64_t ret = (int64_t)tape->ptr + delta;
-    if (ret < 0) assert(0 && "Tape Underflow!");
+cell_t* tape_at(Tape* tape, int64_t offset, const char* loc) {
+    int64_t ret = (int64_t)tape->ptr + offset;
+    if (ret < 0) tape_fail(tape, "tape underflow", loc);
     while ((size_t)ret >= tape->len) da_append(tape, 0);
-    tape->ptr = (size_t)ret;
+    return &tape->items[ret];
+}
+
+void tape_shift(Tape* tape, int64_t delta, const char* loc) {
+    tape_at(tape, delta, loc);
+    tape->ptr = (size_t)((int64_t)tape->ptr + delta);
+}
+
+void tape_set(Tape* tape, int64_t offset, cell_t value, const char* loc) {
+    *tape_at(tape, offset, loc) = value;
+}
+
+#define CELL_MAX ((cell_t)-1)
+
+cell_t cell_add(Tape* tape, cell_t value, int64_t delta, const char* loc) {
+#if defined(OVERFLOW_SATURATE) || defined(OVERFLOW_TRAP)
+    if (delta < 0 ? value < (uint64_t)-delta : (uint64_t)(CELL_MAX - value) < (uint64_t)delta) {
+#ifdef OVERFLOW_TRAP
+        tape_fail(tape, "cell overflow", loc);
+#else
+        return delta < 0 ? 0 : CELL_MAX;
+#endif
+    }
+#endif
+    (void)tape;
+    (void)loc;
+    return value + delta;
 }
 
-void tape_update(Tape* tape, int64_t delta) {
-    tape->items[tape->ptr] += delta;
+void tape_update(Tape* tape, int64_t offset, int64_t delta, const char* loc) {
+    cell_t* cell = tape_at(tape, offset, loc);
+    *cell = cell_add(tape, *cell, delta, loc);
 }
 
 #define tape_jpf(tape, dst) if (tape_curr(tape) == 0) goto dst
 #define tape_jpb(tape, dst) if (tape_curr(tape) != 0) goto dst
 
-void tape_in(Tape* tape) {
+void tape_scan(Tape* tape, int64_t stride, const char* loc) {
+    if (tape_curr(tape) == 0) return;
+    if (sizeof(cell_t) == 1 && stride == 1) {
+        cell_t* found = memchr(&tape->items[tape->ptr], 0, tape->len - tape->ptr);
+        if (found == NULL) tape_shift(tape, (int64_t)(tape->len - tape->ptr), loc);
+        else tape->ptr = (size_t)(found - tape->items);
+    } else if (stride == -1) {
+        while (tape_curr(tape) != 0) {
+            if (tape->ptr == 0) tape_fail(tape, "tape underflow", loc);
+            tape->ptr--;
+        }
+    } else {
+        while (tape_curr(tape) != 0) tape_shift(tape, stride, loc);
+    }
+}
+
+void tape_in(Tape* tape, int64_t offset, const char* loc) {
+    cell_t* cell = tape_at(tape, offset, loc);
     int c = fgetc(stdin);
-    if (c != EOF) tape_assign(tape, c);
+    if (c != EOF) *cell = (cell_t)c;
+#if defined(EOF_ZERO)
+    else *cell = 0;
+#elif defined(EOF_MINUS_ONE)
+    else *cell = CELL_MAX;
+#elif defined(EOF_ERROR)
+    else {
+        tape_fail(tape, "unexpected end of input", loc);
+    }
+#endif
 }
 
-void tape_out(Tape* tape, size_t step) {
+void tape_out(Tape* tape, int64_t offset, size_t step, const char* loc) {
+    cell_t value = *tape_at(tape, offset, loc);
     for (size_t i = 0; i < step; ++i) {
-        printf("%c", tape_curr(tape));
+        putchar((unsigned char)value);
+    }
+}
+
+cell_t cell_mul_add(Tape* tape, cell_t value, cell_t src, int64_t factor, const char* loc) {
+#if defined(OVERFLOW_SATURATE) || defined(OVERFLOW_TRAP)
+    uint64_t magnitude = factor < 0 ? -(uint64_t)factor : (uint64_t)factor;
+    uint64_t room = factor < 0 ? (uint64_t)value : (uint64_t)(CELL_MAX - value);
+    if (magnitude != 0 && src > room / magnitude) {
+        return factor < 0 ? cell_add(tape, 0, -1, loc) : cell_add(tape, CELL_MAX, 1, loc);
     }
+    if (factor < 0) return (cell_t)(value - (uint64_t)src * magnitude);
+#endif
+    (void)tape;
+    (void)loc;
+    return (cell_t)(value + (uint64_t)src * (uint64_t)factor);
 }
 
-void tape_add(Tape* tape, size_t pos) {
-    tape->items[pos] += tape_curr(tape);
+void tape_muladd(Tape* tape, int64_t delta, int64_t factor, const char* loc) {
+    cell_t src = tape_curr(tape);
+    if (src == 0) return;
+    cell_t* dst = tape_at(tape, delta, loc);
+    *dst = cell_mul_add(tape, *dst, src, factor, loc);
 }
 
-void tape_multiple(Tape* tape, size_t step) {
-    tape_assign(tape, (uint8_t)(tape_curr(tape) * step));
+void tape_preset(Tape* tape, size_t start, const cell_t* cells, size_t len) {
+    while (tape->len < start + len) da_append(tape, 0);
+    memcpy(tape->items + start, cells, len * sizeof(cell_t));
 }
 
 void tape_init(Tape* tape) {
@@ -0,0 +1,1082 @@
+//! The WebAssembly backend: a WASI module with the tape in linear memory and
+//! `,`/`.` going through `fd_read`/`fd_write`. The code is built once as a
+//! list of instructions, then written out as either WAT text or the binary
+//! format, so the two always agree.
+
+use super::{BfStr, Config, Eof, Op, OptReport, Overflow, Pipeline, Run};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// How many cells the fixed tape has.
+const TAPE_CELLS: u32 = 1 << 20;
+
+/// The memory layout: an iovec and a result at 0 and 8, the input byte at
+/// 12, then the output buffer, the tape, and the constants after it.
+const IOVEC: i32 = 0;
+const RESULT: i32 = 8;
+const INBYTE: i32 = 12;
+const OUTBUF: i32 = 16;
+const OUTBUF_SIZE: i32 = 4096;
+const TAPE: i32 = OUTBUF + OUTBUF_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+        }
+    }
+}
+
+/// A load or store: its name, opcode and natural alignment as a power of 2.
+#[derive(Debug, Clone, Copy)]
+struct Mem(&'static str, u8, u32);
+
+const I32_LOAD: Mem = Mem("i32.load", 0x28, 2);
+const I32_LOAD8_U: Mem = Mem("i32.load8_u", 0x2d, 0);
+const I32_STORE: Mem = Mem("i32.store", 0x36, 2);
+const I32_STORE8: Mem = Mem("i32.store8", 0x3a, 0);
+
+/// Cells are loaded zero-extended to and stored truncated from an `i64`.
+const CELL_LOADS: [Mem; 4] = [
+    Mem("i64.load8_u", 0x31, 0),
+    Mem("i64.load16_u", 0x33, 1),
+    Mem("i64.load32_u", 0x35, 2),
+    Mem("i64.load", 0x29, 3),
+];
+const CELL_STORES: [Mem; 4] = [
+    Mem("i64.store8", 0x3c, 0),
+    Mem("i64.store16", 0x3d, 1),
+    Mem("i64.store32", 0x3e, 2),
+    Mem("i64.store", 0x37, 3),
+];
+
+/// A numeric instruction with no immediates: its name and opcode.
+#[derive(Debug, Clone, Copy)]
+struct Num(&'static str, u8);
+
+const I32_EQZ: Num = Num("i32.eqz", 0x45);
+const I32_EQ: Num = Num("i32.eq", 0x46);
+const I32_LT_S: Num = Num("i32.lt_s", 0x48);
+const I32_GE_S: Num = Num("i32.ge_s", 0x4e);
+const I32_ADD: Num = Num("i32.add", 0x6a);
+const I32_SUB: Num = Num("i32.sub", 0x6b);
+const I32_OR: Num = Num("i32.or", 0x72);
+const I32_WRAP_I64: Num = Num("i32.wrap_i64", 0xa7);
+const I64_EQZ: Num = Num("i64.eqz", 0x50);
+const I64_EQ: Num = Num("i64.eq", 0x51);
+const I64_LT_U: Num = Num("i64.lt_u", 0x54);
+const I64_GT_U: Num = Num("i64.gt_u", 0x56);
+const I64_ADD: Num = Num("i64.add", 0x7c);
+const I64_SUB: Num = Num("i64.sub", 0x7d);
+const I64_MUL: Num = Num("i64.mul", 0x7e);
+const I64_EXTEND_I32_S: Num = Num("i64.extend_i32_s", 0xac);
+
+/// The functions, imported first, in index order.
+#[derive(Debug, Clone, Copy)]
+enum Func {
+    FdRead,
+    FdWrite,
+    ProcExit,
+    Write,
+    Flush,
+    PutByte,
+    PutBytes,
+    PutBuf,
+    GetByte,
+    Fail,
+    Start,
+}
+
+impl Func {
+    const IMPORTS: [Func; 3] = [Func::FdRead, Func::FdWrite, Func::ProcExit];
+
+    fn name(self) -> &'static str {
+        match self {
+            Func::FdRead => "fd_read",
+            Func::FdWrite => "fd_write",
+            Func::ProcExit => "proc_exit",
+            Func::Write => "write",
+            Func::Flush => "flush",
+            Func::PutByte => "putbyte",
+            Func::PutBytes => "putbytes",
+            Func::PutBuf => "putbuf",
+            Func::GetByte => "getbyte",
+            Func::Fail => "fail",
+            Func::Start => "_start",
+        }
+    }
+
+    fn signature(self) -> (&'static [ValType], &'static [ValType]) {
+        use ValType::I32;
+        match self {
+            Func::FdRead | Func::FdWrite => (&[I32, I32, I32, I32], &[I32]),
+            Func::ProcExit | Func::PutByte => (&[I32], &[]),
+            Func::Write => (&[I32, I32, I32], &[]),
+            Func::Flush | Func::Start => (&[], &[]),
+            Func::PutBytes | Func::PutBuf => (&[I32, I32], &[]),
+            Func::GetByte => (&[], &[I32]),
+            Func::Fail => (&[I32, I32, I32, I32], &[]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Ins {
+    /// Shows up in the text only.
+    Comment(String),
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Unreachable,
+    Call(Func),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    /// The output buffer length is the only global.
+    OutLenGet,
+    OutLenSet,
+    I32Const(i32),
+    I64Const(i64),
+    Memory(Mem),
+    Numeric(Num),
+}
+
+use Ins::*;
+
+struct Function {
+    func: Func,
+    /// The locals after the params.
+    locals: Vec<ValType>,
+    body: Vec<Ins>,
+}
+
+/// A data segment placed at a fixed address.
+struct Data {
+    address: i32,
+    bytes: Vec<u8>,
+}
+
+struct Module {
+    pages: u32,
+    functions: Vec<Function>,
+    data: Vec<Data>,
+}
+
+// locals of `_start`
+const P: u32 = 0;
+const ADDR: u32 = 1;
+const VALUE: u32 = 2;
+const MAGNITUDE: u32 = 3;
+const SOURCE: u32 = 4;
+const TOO_BIG: u32 = 5;
+
+/// Allocates the constants after the tape.
+struct Constants {
+    next: i32,
+    data: Vec<Data>,
+}
+
+impl Constants {
+    fn add(&mut self, bytes: &[u8]) -> (i32, i32) {
+        let address = self.next;
+        self.next += bytes.len() as i32;
+        self.data.push(Data {
+            address,
+            bytes: bytes.to_vec(),
+        });
+        (address, bytes.len() as i32)
+    }
+}
+
+/// The messages `$fail` puts together.
+struct Messages {
+    error: (i32, i32),
+    at: (i32, i32),
+    newline: (i32, i32),
+    underflow: (i32, i32),
+    end: (i32, i32),
+    overflow: (i32, i32),
+    eof: (i32, i32),
+}
+
+fn runtime(messages: &Messages) -> Vec<Function> {
+    let write = |fd: i32, (address, len): (i32, i32)| {
+        vec![
+            I32Const(fd),
+            I32Const(address),
+            I32Const(len),
+            Call(Func::Write),
+        ]
+    };
+    let mut fail = vec![Call(Func::Flush)];
+    fail.extend(write(2, messages.error));
+    fail.extend([I32Const(2), LocalGet(0), LocalGet(1), Call(Func::Write)]);
+    fail.extend(write(2, messages.at));
+    fail.extend([I32Const(2), LocalGet(2), LocalGet(3), Call(Func::Write)]);
+    fail.extend(write(2, messages.newline));
+    fail.extend([I32Const(3), Call(Func::ProcExit), Unreachable]);
+
+    vec![
+        // write(fd, address, len) until it's all written or nothing more
+        // goes out, exiting with status 3 on an error
+        Function {
+            func: Func::Write,
+            locals: vec![],
+            body: vec![
+                Block,
+                Loop,
+                LocalGet(2),
+                Numeric(I32_EQZ),
+                BrIf(1),
+                I32Const(IOVEC),
+                LocalGet(1),
+                Memory(I32_STORE),
+                I32Const(IOVEC + 4),
+                LocalGet(2),
+                Memory(I32_STORE),
+                LocalGet(0),
+                I32Const(IOVEC),
+                I32Const(1),
+                I32Const(RESULT),
+                Call(Func::FdWrite),
+                If,
+                I32Const(3),
+                Call(Func::ProcExit),
+                Unreachable,
+                End,
+                I32Const(RESULT),
+                Memory(I32_LOAD),
+                Numeric(I32_EQZ),
+                BrIf(1),
+                LocalGet(1),
+                I32Const(RESULT),
+                Memory(I32_LOAD),
+                Numeric(I32_ADD),
+                LocalSet(1),
+                LocalGet(2),
+                I32Const(RESULT),
+                Memory(I32_LOAD),
+                Numeric(I32_SUB),
+                LocalSet(2),
+                Br(0),
+                End,
+                End,
+            ],
+        },
+        Function {
+            func: Func::Flush,
+            locals: vec![],
+            body: vec![
+                I32Const(1),
+                I32Const(OUTBUF),
+                OutLenGet,
+                Call(Func::Write),
+                I32Const(0),
+                OutLenSet,
+            ],
+        },
+        Function {
+            func: Func::PutByte,
+            locals: vec![],
+            body: vec![
+                OutLenGet,
+                I32Const(OUTBUF),
+                Numeric(I32_ADD),
+                LocalGet(0),
+                Memory(I32_STORE8),
+                OutLenGet,
+                I32Const(1),
+                Numeric(I32_ADD),
+                OutLenSet,
+                OutLenGet,
+                I32Const(OUTBUF_SIZE),
+                Numeric(I32_EQ),
+                If,
+                Call(Func::Flush),
+                End,
+            ],
+        },
+        // put `byte` `count` times
+        Function {
+            func: Func::PutBytes,
+            locals: vec![],
+            body: vec![
+                Block,
+                Loop,
+                LocalGet(1),
+                Numeric(I32_EQZ),
+                BrIf(1),
+                LocalGet(0),
+                Call(Func::PutByte),
+                LocalGet(1),
+                I32Const(1),
+                Numeric(I32_SUB),
+                LocalSet(1),
+                Br(0),
+                End,
+                End,
+            ],
+        },
+        // put the `len` bytes at `address`
+        Function {
+            func: Func::PutBuf,
+            locals: vec![],
+            body: vec![
+                Block,
+                Loop,
+                LocalGet(1),
+                Numeric(I32_EQZ),
+                BrIf(1),
+                LocalGet(0),
+                Memory(I32_LOAD8_U),
+                Call(Func::PutByte),
+                LocalGet(0),
+                I32Const(1),
+                Numeric(I32_ADD),
+                LocalSet(0),
+                LocalGet(1),
+                I32Const(1),
+                Numeric(I32_SUB),
+                LocalSet(1),
+                Br(0),
+                End,
+                End,
+            ],
+        },
+        // the next input byte, or -1 at the end of input
+        Function {
+            func: Func::GetByte,
+            locals: vec![],
+            body: vec![
+                Call(Func::Flush),
+                I32Const(IOVEC),
+                I32Const(INBYTE),
+                Memory(I32_STORE),
+                I32Const(IOVEC + 4),
+                I32Const(1),
+                Memory(I32_STORE),
+                I32Const(0),
+                I32Const(IOVEC),
+                I32Const(1),
+                I32Const(RESULT),
+                Call(Func::FdRead),
+                I32Const(RESULT),
+                Memory(I32_LOAD),
+                Numeric(I32_EQZ),
+                Numeric(I32_OR),
+                If,
+                I32Const(-1),
+                Return,
+                End,
+                I32Const(INBYTE),
+                Memory(I32_LOAD8_U),
+            ],
+        },
+        // print `error: <message> at <location>` and exit with status 3
+        Function {
+            func: Func::Fail,
+            locals: vec![],
+            body: fail,
+        },
+    ]
+}
+
+/// Emits the body of `_start` one op at a time.
+struct Emitter<'a> {
+    config: &'a Config,
+    body: Vec<Ins>,
+    constants: Constants,
+    messages: Messages,
+    /// Bytes per cell.
+    size: usize,
+    max: u64,
+    tape_end: i32,
+    /// The location of the op being emitted, as the message constant,
+    /// made on first use.
+    loc: Option<(i32, i32)>,
+    loc_text: String,
+}
+
+impl Emitter<'_> {
+    fn emit(&mut self, ins: impl IntoIterator<Item = Ins>) {
+        self.body.extend(ins);
+    }
+
+    fn load(&self) -> Ins {
+        Memory(CELL_LOADS[self.size.trailing_zeros() as usize])
+    }
+
+    fn store(&self) -> Ins {
+        Memory(CELL_STORES[self.size.trailing_zeros() as usize])
+    }
+
+    fn fail(&mut self, message: (i32, i32)) {
+        let loc = match self.loc {
+            Some(loc) => loc,
+            None => {
+                let loc = self.constants.add(self.loc_text.as_bytes());
+                self.loc = Some(loc);
+                loc
+            }
+        };
+        self.emit([
+            I32Const(message.0),
+            I32Const(message.1),
+            I32Const(loc.0),
+            I32Const(loc.1),
+            Call(Func::Fail),
+        ]);
+    }
+
+    fn disp(&self, cells: isize) -> i32 {
+        // far offsets only ever fail the check
+        (cells * self.size as isize).clamp(-(1 << 30), 1 << 30) as i32
+    }
+
+    /// Fail unless the local `addr` points into the tape.
+    fn check(&mut self, addr: u32) {
+        self.emit([LocalGet(addr), I32Const(TAPE), Numeric(I32_LT_S), If]);
+        self.fail(self.messages.underflow);
+        self.emit([
+            End,
+            LocalGet(addr),
+            I32Const(self.tape_end),
+            Numeric(I32_GE_S),
+            If,
+        ]);
+        self.fail(self.messages.end);
+        self.emit([End]);
+    }
+
+    /// The local holding the address of `cell[ptr + offset]`, checked
+    /// against the ends of the tape unless it's the current cell.
+    fn cell(&mut self, offset: isize) -> u32 {
+        if offset == 0 {
+            return P;
+        }
+        let disp = self.disp(offset);
+        self.emit([
+            LocalGet(P),
+            I32Const(disp),
+            Numeric(I32_ADD),
+            LocalSet(ADDR),
+        ]);
+        self.check(ADDR);
+        ADDR
+    }
+
+    fn shift(&mut self, delta: isize) {
+        let disp = self.disp(delta);
+        self.emit([LocalGet(P), I32Const(disp), Numeric(I32_ADD), LocalSet(P)]);
+        self.check(P);
+    }
+
+    /// Store `value` plus `magnitude`, or minus it if `negative`, to the
+    /// cell at `addr` under the overflow policy, also going out of range if
+    /// `too_big` says so.
+    fn checked_add(&mut self, addr: u32, negative: bool, too_big: bool) {
+        if negative {
+            self.emit([
+                LocalGet(VALUE),
+                LocalGet(MAGNITUDE),
+                Numeric(I64_LT_U),
+                LocalGet(VALUE),
+                LocalGet(MAGNITUDE),
+                Numeric(I64_SUB),
+                LocalSet(VALUE),
+            ]);
+        } else {
+            // a carry leaves the sum below either side
+            self.emit([
+                LocalGet(VALUE),
+                LocalGet(MAGNITUDE),
+                Numeric(I64_ADD),
+                LocalTee(VALUE),
+                LocalGet(MAGNITUDE),
+                Numeric(I64_LT_U),
+            ]);
+            if self.size < 8 {
+                self.emit([
+                    LocalGet(VALUE),
+                    I64Const(self.max as i64),
+                    Numeric(I64_GT_U),
+                    Numeric(I32_OR),
+                ]);
+            }
+        }
+        if too_big {
+            self.emit([LocalGet(TOO_BIG), Numeric(I32_OR)]);
+        }
+        self.emit([If]);
+        match self.config.overflow {
+            Overflow::Trap => self.fail(self.messages.overflow),
+            _ => {
+                let bound = if negative { 0 } else { self.max as i64 };
+                self.emit([I64Const(bound), LocalSet(VALUE)]);
+            }
+        }
+        let store = self.store();
+        self.emit([End, LocalGet(addr), LocalGet(VALUE), store]);
+    }
+
+    fn update(&mut self, offset: isize, delta: isize) {
+        let addr = self.cell(offset);
+        let (load, store) = (self.load(), self.store());
+        if self.config.overflow == Overflow::Wrap {
+            // the store drops whatever doesn't fit
+            self.emit([
+                LocalGet(addr),
+                LocalGet(addr),
+                load,
+                I64Const(delta as i64),
+                Numeric(I64_ADD),
+                store,
+            ]);
+            return;
+        }
+        self.emit([
+            LocalGet(addr),
+            load,
+            LocalSet(VALUE),
+            I64Const(delta.unsigned_abs() as i64),
+            LocalSet(MAGNITUDE),
+        ]);
+        self.checked_add(addr, delta < 0, false);
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: isize) {
+        let (load, store) = (self.load(), self.store());
+        self.emit([
+            LocalGet(P),
+            load.clone(),
+            LocalTee(SOURCE),
+            Numeric(I64_EQZ),
+            Numeric(I32_EQZ),
+            If,
+        ]);
+        let addr = self.cell(offset);
+        if self.config.overflow == Overflow::Wrap {
+            self.emit([
+                LocalGet(addr),
+                LocalGet(addr),
+                load,
+                LocalGet(SOURCE),
+                I64Const(factor as i64),
+                Numeric(I64_MUL),
+                Numeric(I64_ADD),
+                store,
+            ]);
+        } else {
+            let magnitude = factor.unsigned_abs() as u64;
+            // the product only fits in 64 bits up to this source
+            let limit = u64::MAX / magnitude.max(1);
+            self.emit([
+                LocalGet(SOURCE),
+                I64Const(limit as i64),
+                Numeric(I64_GT_U),
+                LocalSet(TOO_BIG),
+                LocalGet(SOURCE),
+                I64Const(magnitude as i64),
+                Numeric(I64_MUL),
+                LocalSet(MAGNITUDE),
+                LocalGet(addr),
+                load,
+                LocalSet(VALUE),
+            ]);
+            self.checked_add(addr, factor < 0, true);
+        }
+        self.emit([End]);
+    }
+
+    fn scan(&mut self, stride: isize) {
+        let load = self.load();
+        self.emit([Block, Loop, LocalGet(P), load, Numeric(I64_EQZ), BrIf(1)]);
+        self.shift(stride);
+        self.emit([Br(0), End, End]);
+    }
+
+    fn input(&mut self, offset: isize) {
+        let addr = self.cell(offset);
+        let store = self.store();
+        self.emit([
+            Call(Func::GetByte),
+            Numeric(I64_EXTEND_I32_S),
+            LocalTee(VALUE),
+            I64Const(-1),
+            Numeric(I64_EQ),
+            If,
+        ]);
+        match self.config.eof {
+            Eof::Unchanged => (),
+            Eof::Zero => self.emit([LocalGet(addr), I64Const(0), store.clone()]),
+            Eof::MinusOne => self.emit([LocalGet(addr), I64Const(-1), store.clone()]),
+            Eof::Error => self.fail(self.messages.eof),
+        }
+        self.emit([Else, LocalGet(addr), LocalGet(VALUE), store, End]);
+    }
+
+    fn output(&mut self, offset: isize, count: isize) {
+        let addr = self.cell(offset);
+        let load = self.load();
+        self.emit([
+            LocalGet(addr),
+            load,
+            Numeric(I32_WRAP_I64),
+            I32Const(count as i32),
+            Call(Func::PutBytes),
+        ]);
+    }
+
+    fn set(&mut self, offset: isize, value: isize) {
+        let addr = self.cell(offset);
+        let store = self.store();
+        self.emit([LocalGet(addr), I64Const(value as i64), store]);
+    }
+
+    fn preset(
+        &mut self,
+        output: &[u8],
+        runs: &[Run],
+        ptr: usize,
+        first: bool,
+        last: bool,
+    ) -> io::Result<()> {
+        if !output.is_empty() {
+            let (address, len) = self.constants.add(output);
+            self.emit([I32Const(address), I32Const(len), Call(Func::PutBuf)]);
+        }
+        // nobody can look at the tape once the program ends
+        if last {
+            return Ok(());
+        }
+        if runs.last().map_or(0, Run::end) > TAPE_CELLS as usize || ptr >= TAPE_CELLS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the program needs more than {} cells", TAPE_CELLS),
+            ));
+        }
+        // a preset only ever starts a program, with the tape still zeroed,
+        // so the cells can go straight into memory
+        debug_assert!(first, "a preset must be the first op");
+        for run in runs {
+            let bytes = run
+                .cells
+                .iter()
+                .flat_map(|cell| cell.to_le_bytes()[..self.size].to_vec())
+                .collect();
+            self.constants.data.push(Data {
+                address: TAPE + (run.start * self.size) as i32,
+                bytes,
+            });
+        }
+        let disp = self.disp(ptr as isize);
+        self.emit([I32Const(TAPE + disp), LocalSet(P)]);
+        self.check(P);
+        Ok(())
+    }
+}
+
+/// `bytes` as the contents of a WAT string.
+fn wat_string(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => out.push_str(&format!("\\{}", byte as char)),
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:02x}", byte)),
+        }
+    }
+    out
+}
+
+fn signature_wat(func: Func) -> String {
+    let (params, results) = func.signature();
+    let mut out = String::new();
+    if !params.is_empty() {
+        let params: Vec<_> = params.iter().map(|param| param.name()).collect();
+        out.push_str(&format!(" (param {})", params.join(" ")));
+    }
+    if !results.is_empty() {
+        let results: Vec<_> = results.iter().map(|result| result.name()).collect();
+        out.push_str(&format!(" (result {})", results.join(" ")));
+    }
+    out
+}
+
+impl Module {
+    fn wat(&self) -> String {
+        let mut out = String::from("(module\n");
+        for func in Func::IMPORTS {
+            out.push_str(&format!(
+                "  (import \"wasi_snapshot_preview1\" \"{name}\" (func ${name}{}))\n",
+                signature_wat(func),
+                name = func.name()
+            ));
+        }
+        out.push_str(&format!("  (memory (export \"memory\") {})\n", self.pages));
+        out.push_str("  (global $outlen (mut i32) (i32.const 0))\n");
+        for function in &self.functions {
+            let func = function.func;
+            out.push_str(&format!("\n  (func ${}", func.name()));
+            if let Func::Start = func {
+                out.push_str(" (export \"_start\")");
+            }
+            out.push_str(&signature_wat(func));
+            if !function.locals.is_empty() {
+                let locals: Vec<_> = function.locals.iter().map(|local| local.name()).collect();
+                out.push_str(&format!(" (local {})", locals.join(" ")));
+            }
+            out.push('\n');
+            let mut depth = 2;
+            for ins in &function.body {
+                if let End | Else = ins {
+                    depth -= 1;
+                }
+                let text = match ins {
+                    Comment(text) => format!(";; {}", text),
+                    Block => String::from("block"),
+                    Loop => String::from("loop"),
+                    If => String::from("if"),
+                    Else => String::from("else"),
+                    End => String::from("end"),
+                    Br(depth) => format!("br {}", depth),
+                    BrIf(depth) => format!("br_if {}", depth),
+                    Return => String::from("return"),
+                    Unreachable => String::from("unreachable"),
+                    Call(func) => format!("call ${}", func.name()),
+                    LocalGet(idx) => format!("local.get {}", idx),
+                    LocalSet(idx) => format!("local.set {}", idx),
+                    LocalTee(idx) => format!("local.tee {}", idx),
+                    OutLenGet => String::from("global.get $outlen"),
+                    OutLenSet => String::from("global.set $outlen"),
+                    I32Const(value) => format!("i32.const {}", value),
+                    I64Const(value) => format!("i64.const {}", value),
+                    Memory(mem) => String::from(mem.0),
+                    Numeric(num) => String::from(num.0),
+                };
+                out.push_str(&format!("{:indent$}{}\n", "", text, indent = depth * 2));
+                if let Block | Loop | If | Else = ins {
+                    depth += 1;
+                }
+            }
+            out.push_str("  )\n");
+        }
+        out.push('\n');
+        for data in &self.data {
+            out.push_str(&format!(
+                "  (data (i32.const {}) \"{}\")\n",
+                data.address,
+                wat_string(&data.bytes)
+            ));
+        }
+        out.push_str(")\n");
+        out
+    }
+
+    fn binary(&self) -> Vec<u8> {
+        let mut types: Vec<(&[ValType], &[ValType])> = Vec::new();
+        let mut type_of = |func: Func| {
+            let signature = func.signature();
+            match types.iter().position(|other| *other == signature) {
+                Some(idx) => idx as u32,
+                None => {
+                    types.push(signature);
+                    types.len() as u32 - 1
+                }
+            }
+        };
+        let imports: Vec<u32> = Func::IMPORTS.iter().map(|func| type_of(*func)).collect();
+        let functions: Vec<u32> = self.functions.iter().map(|f| type_of(f.func)).collect();
+
+        let mut out = b"\0asm".to_vec();
+        out.extend_from_slice(&1u32.to_le_bytes());
+
+        let mut section = Vec::new();
+        uleb(&mut section, types.len() as u64);
+        for (params, results) in &types {
+            section.push(0x60);
+            for list in [params, results] {
+                uleb(&mut section, list.len() as u64);
+                section.extend(list.iter().map(|ty| ty.code()));
+            }
+        }
+        push_section(&mut out, 1, &section);
+
+        let mut section = Vec::new();
+        uleb(&mut section, imports.len() as u64);
+        for (func, ty) in Func::IMPORTS.iter().zip(&imports) {
+            name(&mut section, "wasi_snapshot_preview1");
+            name(&mut section, func.name());
+            section.push(0x00);
+            uleb(&mut section, *ty as u64);
+        }
+        push_section(&mut out, 2, &section);
+
+        let mut section = Vec::new();
+        uleb(&mut section, functions.len() as u64);
+        for ty in &functions {
+            uleb(&mut section, *ty as u64);
+        }
+        push_section(&mut out, 3, &section);
+
+        let mut section = vec![1, 0x00];
+        uleb(&mut section, self.pages as u64);
+        push_section(&mut out, 5, &section);
+
+        // one mutable i32 starting at 0
+        push_section(&mut out, 6, &[1, 0x7f, 0x01, 0x41, 0, 0x0b]);
+
+        let mut section = vec![2];
+        name(&mut section, "memory");
+        section.extend([0x02, 0]);
+        name(&mut section, "_start");
+        section.push(0x00);
+        uleb(&mut section, Func::Start as u64);
+        push_section(&mut out, 7, &section);
+
+        let mut section = Vec::new();
+        uleb(&mut section, self.functions.len() as u64);
+        for function in &self.functions {
+            let mut body = Vec::new();
+            uleb(&mut body, function.locals.len() as u64);
+            for local in &function.locals {
+                body.extend([1, local.code()]);
+            }
+            for ins in &function.body {
+                encode(&mut body, ins);
+            }
+            body.push(0x0b);
+            uleb(&mut section, body.len() as u64);
+            section.extend(body);
+        }
+        push_section(&mut out, 10, &section);
+
+        let mut section = Vec::new();
+        uleb(&mut section, self.data.len() as u64);
+        for data in &self.data {
+            section.extend([0x00, 0x41]);
+            sleb(&mut section, data.address as i64);
+            section.push(0x0b);
+            uleb(&mut section, data.bytes.len() as u64);
+            section.extend(&data.bytes);
+        }
+        push_section(&mut out, 11, &section);
+        out
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend(name.as_bytes());
+}
+
+fn push_section(out: &mut Vec<u8>, id: u8, section: &[u8]) {
+    out.push(id);
+    uleb(out, section.len() as u64);
+    out.extend(section);
+}
+
+fn encode(out: &mut Vec<u8>, ins: &Ins) {
+    match ins {
+        Comment(_) => (),
+        Block => out.extend([0x02, 0x40]),
+        Loop => out.extend([0x03, 0x40]),
+        If => out.extend([0x04, 0x40]),
+        Else => out.push(0x05),
+        End => out.push(0x0b),
+        Br(depth) => {
+            out.push(0x0c);
+            uleb(out, *depth as u64);
+        }
+        BrIf(depth) => {
+            out.push(0x0d);
+            uleb(out, *depth as u64);
+        }
+        Return => out.push(0x0f),
+        Unreachable => out.push(0x00),
+        Call(func) => {
+            out.push(0x10);
+            uleb(out, *func as u64);
+        }
+        LocalGet(idx) | LocalSet(idx) | LocalTee(idx) => {
+            out.push(match ins {
+                LocalGet(_) => 0x20,
+                LocalSet(_) => 0x21,
+                _ => 0x22,
+            });
+            uleb(out, *idx as u64);
+        }
+        OutLenGet => out.extend([0x23, 0]),
+        OutLenSet => out.extend([0x24, 0]),
+        I32Const(value) => {
+            out.push(0x41);
+            sleb(out, *value as i64);
+        }
+        I64Const(value) => {
+            out.push(0x42);
+            sleb(out, *value);
+        }
+        Memory(Mem(_, opcode, align)) => {
+            out.push(*opcode);
+            uleb(out, *align as u64);
+            uleb(out, 0);
+        }
+        Numeric(Num(_, opcode)) => out.push(*opcode),
+    }
+}
+
+impl BfStr {
+    fn wasm_module(&self, pipeline: &Pipeline, config: &Config) -> io::Result<(Module, OptReport)> {
+        let (ops, report) = self.optimize_report(pipeline, config);
+        let size = config.cell_bits.bits() as usize / 8;
+        let tape_end = TAPE + (TAPE_CELLS as usize * size) as i32;
+        let mut constants = Constants {
+            next: tape_end,
+            data: Vec::new(),
+        };
+        let messages = Messages {
+            error: constants.add(b"error: "),
+            at: constants.add(b" at "),
+            newline: constants.add(b"\n"),
+            underflow: constants.add(b"tape underflow"),
+            end: constants.add(b"end of tape"),
+            overflow: constants.add(b"cell overflow"),
+            eof: constants.add(b"unexpected end of input"),
+        };
+        let mut emitter = Emitter {
+            config,
+            body: vec![I32Const(TAPE), LocalSet(P)],
+            constants,
+            messages,
+            size,
+            max: u64::MAX >> (64 - size * 8),
+            tape_end,
+            loc: None,
+            loc_text: String::new(),
+        };
+        for (idx, op) in ops.iter().enumerate() {
+            emitter.loc = None;
+            emitter.loc_text = format!("line {}, column {}", op.span.line, op.span.column);
+            emitter.emit([Comment(op.to_string())]);
+            let load = emitter.load();
+            match op.operator {
+                Op::Upd => emitter.update(op.offset, op.operand),
+                Op::Shf => emitter.shift(op.operand),
+                Op::Scan { stride } => emitter.scan(stride),
+                Op::Acp => emitter.input(op.offset),
+                Op::Out => emitter.output(op.offset, op.operand),
+                Op::Jpf => {
+                    emitter.emit([Block, LocalGet(P), load, Numeric(I64_EQZ), BrIf(0), Loop])
+                }
+                Op::Jpb => emitter.emit([
+                    LocalGet(P),
+                    load,
+                    Numeric(I64_EQZ),
+                    Numeric(I32_EQZ),
+                    BrIf(0),
+                    End,
+                    End,
+                ]),
+                Op::Set => emitter.set(op.offset, op.operand),
+                Op::MulAdd { offset, factor } => emitter.mul_add(offset, factor),
+                Op::Preset {
+                    ref output,
+                    ref runs,
+                    ptr,
+                } => emitter.preset(output, runs, ptr, idx == 0, idx + 1 == ops.len())?,
+            }
+        }
+        emitter.emit([Call(Func::Flush)]);
+
+        let mut functions = runtime(&emitter.messages);
+        functions.push(Function {
+            func: Func::Start,
+            locals: {
+                use ValType::*;
+                vec![I32, I32, I64, I64, I64, I32]
+            },
+            body: emitter.body,
+        });
+        let memory_end = emitter.constants.next as u32;
+        let module = Module {
+            pages: memory_end.div_ceil(1 << 16),
+            functions,
+            data: emitter.constants.data,
+        };
+        Ok((module, report))
+    }
+
+    pub(super) fn _wasm(
+        &self,
+        mut write: impl Write,
+        pipeline: &Pipeline,
+        config: &Config,
+        binary: bool,
+    ) -> io::Result<OptReport> {
+        let (module, report) = self.wasm_module(pipeline, config)?;
+        if binary {
+            write.write_all(&module.binary())?;
+        } else {
+            write.write_all(module.wat().as_bytes())?;
+        }
+        Ok(report)
+    }
+
+    /// Write a WASI module, as WAT text or in the binary format.
+    pub fn wasm(
+        &self,
+        save_path: &Path,
+        pipeline: &Pipeline,
+        config: &Config,
+        binary: bool,
+    ) -> io::Result<OptReport> {
+        let file = File::create(save_path)?;
+        self._wasm(file, pipeline, config, binary)
+    }
+}
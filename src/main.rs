@@ -1,54 +1,351 @@
 mod bf_str;
-use bf_str::BfStr;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use bf_str::{
+    BfStr, BuildOptions, CcOptions, CellBits, Config, Eof, IrFormat, Overflow, Pass, Pipeline,
+    Target,
+};
+use clap::{Args, Parser, Subcommand};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process;
 
 #[derive(Parser)]
 struct Cli {
-    /// Subcommand, run or compile
+    /// Subcommand, run, compile, build or dump-ir
     #[command(subcommand)]
     command: Commands,
 }
 
+// Options that change what a program means, shared by `run` and `compile`
+// so that both agree on the result.
+#[derive(Args)]
+struct TapeArgs {
+    /// Width of each tape cell in bits: 8, 16, 32 or 64
+    #[arg(long, default_value_t = CellBits::U8)]
+    cell_bits: CellBits,
+    /// What happens when a cell goes out of range: wrap, saturate or trap
+    #[arg(long, default_value_t = Overflow::Wrap)]
+    overflow: Overflow,
+    /// What `,` stores at end of input: unchanged, zero, minus-one or error
+    #[arg(long, default_value_t = Eof::Unchanged)]
+    eof: Eof,
+}
+
+impl TapeArgs {
+    fn config(&self) -> Config {
+        Config {
+            cell_bits: self.cell_bits,
+            overflow: self.overflow,
+            eof: self.eof,
+            ..Config::default()
+        }
+    }
+}
+
+// Which optimizations to apply, shared by `run` and `compile`.
+#[derive(Args)]
+struct OptArgs {
+    /// Optimization level from 0 to 3, e.g. `-O2`; `-O` alone is `-O3`
+    #[arg(
+        short = 'O',
+        long = "opt-level",
+        alias = "optimize",
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        default_value_t = 0,
+        default_missing_value = "3",
+        value_parser = clap::value_parser!(u8).range(0..=3),
+    )]
+    level: u8,
+    /// Run these passes in this order instead of the ones of the level:
+    /// clear-loop, mul-loop, scan-loop, offset, dead-loop and const-fold
+    #[arg(long, value_delimiter = ',', value_name = "PASSES")]
+    passes: Option<Vec<Pass>>,
+    /// Print the operations to stderr after every pass
+    #[arg(long)]
+    print_after_all: bool,
+}
+
+impl OptArgs {
+    fn pipeline(&self) -> Pipeline {
+        let mut pipeline = Pipeline::level(self.level);
+        if let Some(passes) = &self.passes {
+            pipeline.passes = passes.clone();
+        }
+        pipeline.print_after_all = self.print_after_all;
+        pipeline
+    }
+}
+
+// How the C backend writes its code.
+#[derive(Args)]
+struct CcArgs {
+    /// Build C loops out of `goto` labels instead of `while` blocks
+    #[arg(long, conflicts_with = "lean")]
+    goto: bool,
+    /// Write C with a fixed static tape and inline pointer arithmetic instead of the tape runtime
+    #[arg(long)]
+    lean: bool,
+    /// How many cells the `--lean` tape has
+    #[arg(
+        long,
+        value_name = "CELLS",
+        default_value_t = 30000,
+        requires = "lean",
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    tape_cells: u32,
+    /// Make `--lean` code stop with an error when the pointer leaves the tape
+    #[arg(long, requires = "lean")]
+    bounds_check: bool,
+}
+
+impl CcArgs {
+    fn options(&self) -> CcOptions {
+        CcOptions {
+            goto: self.goto,
+            lean: self.lean,
+            tape_cells: self.tape_cells as usize,
+            bounds_check: self.bounds_check,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Run {
+        #[command(flatten)]
+        tape: TapeArgs,
+        /// Stop with an error after executing this many operations
+        #[arg(long)]
+        max_steps: Option<u64>,
+        #[command(flatten)]
+        opt: OptArgs,
         /// The path of BrainFuck source file
         #[arg(default_value = "./sample/hello.bf")]
         file: PathBuf,
     },
     Compile {
+        #[command(flatten)]
+        tape: TapeArgs,
         #[arg(short, long, default_value = "./")]
         out: PathBuf,
-        /// Compile with some optimizations
-        #[arg(short = 'O', long)]
-        optimize: bool,
+        /// What to compile to: c, llvm, x86_64-asm, elf, wasm (WAT text), wasm-binary or rust
+        #[arg(long, default_value_t = Target::C)]
+        target: Target,
+        #[command(flatten)]
+        cc: CcArgs,
+        #[command(flatten)]
+        opt: OptArgs,
         /// The path of BrainFuck source file
         #[arg(default_value = "./sample/hello.bf")]
         file: PathBuf,
     },
+    /// Compile a program to C and build it into an executable with the C
+    /// compiler from `$CC`, or else `cc`, `gcc` or `clang`
+    Build {
+        #[command(flatten)]
+        tape: TapeArgs,
+        /// Where to write the executable [default: the file name without its extension]
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Flags for the C compiler, split on whitespace
+        #[arg(long, default_value = "-O2", allow_hyphen_values = true)]
+        cflags: String,
+        #[command(flatten)]
+        cc: CcArgs,
+        #[command(flatten)]
+        opt: OptArgs,
+        /// The path of BrainFuck source file
+        #[arg(default_value = "./sample/hello.bf")]
+        file: PathBuf,
+    },
+    /// Print the operations a program compiles to
+    DumpIr {
+        #[command(flatten)]
+        tape: TapeArgs,
+        /// Print the operations after optimizing, at level 3 unless `--level` says otherwise
+        #[arg(long)]
+        optimized: bool,
+        /// Print the operations after this optimization level, from 0 to 3
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=3))]
+        level: Option<u8>,
+        /// Output format: text or json
+        #[arg(long, default_value_t = IrFormat::Text)]
+        format: IrFormat,
+        /// The path of BrainFuck source file
+        #[arg(default_value = "./sample/hello.bf")]
+        file: PathBuf,
+    },
+}
+
+/// Exit status when the program itself fails at runtime, as opposed to
+/// failing to load.
+const EXIT_RUNTIME: i32 = 3;
+
+/// The name of what `file` compiles to: its own name with a `.bf` suffix
+/// swapped for `extension`, which is appended to any other name, since
+/// `with_extension` would cut `hello.v2` to `hello.c`.
+fn out_name(file: &Path, extension: &str) -> Option<OsString> {
+    let mut name = match file.extension() {
+        Some(bf) if bf == "bf" => file.file_stem()?.to_os_string(),
+        _ => file.file_name()?.to_os_string(),
+    };
+    if !extension.is_empty() {
+        name.push(".");
+        name.push(extension);
+    }
+    Some(name)
+}
+
+/// Load a source file, or report why it couldn't be loaded and exit.
+fn load(file: &Path) -> BfStr {
+    match BfStr::from_file(file) {
+        Ok(bf_str) => bf_str,
+        Err(err) => {
+            eprintln!("error: {}: {}", file.display(), err);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let args = Cli::parse();
     match &args.command {
-        Commands::Run { file } => {
-            let bf_str = BfStr::from_file(file).unwrap();
-            bf_str.interpret();
+        Commands::Run {
+            tape,
+            max_steps,
+            opt,
+            file,
+        } => {
+            let bf_str = load(file);
+            let config = Config {
+                step_limit: *max_steps,
+                ..tape.config()
+            };
+            if let Err(err) = bf_str.interpret(&opt.pipeline(), &config) {
+                eprintln!("error: {}: {}", file.display(), err);
+                process::exit(EXIT_RUNTIME);
+            }
         }
         Commands::Compile {
-            optimize,
+            tape,
+            opt,
             out,
+            target,
+            cc,
             file,
         } => {
-            let bf_str = BfStr::from_file(file).unwrap();
-            if let Some(basename) = file.file_stem() {
-                let mut c_path = PathBuf::from(out);
-                c_path.push(basename);
-                c_path.set_extension("c");
-                bf_str.cc(&c_path, *optimize);
-                println!("Successfully compiled to {:?}", &c_path);
+            let bf_str = load(file);
+            if let Some(name) = out_name(file, target.extension()) {
+                let out_path = out.join(name);
+                let report = match bf_str.compile(
+                    *target,
+                    &out_path,
+                    &opt.pipeline(),
+                    &tape.config(),
+                    &cc.options(),
+                ) {
+                    Ok(report) => report,
+                    Err(err) => {
+                        eprintln!("error: {}: {}", out_path.display(), err);
+                        process::exit(1);
+                    }
+                };
+                if report.dead_loops > 0 {
+                    println!("Removed {} dead loop(s)", report.dead_loops);
+                }
+                println!("Successfully compiled to {:?}", &out_path);
             }
         }
+        Commands::Build {
+            tape,
+            out,
+            cflags,
+            cc,
+            opt,
+            file,
+        } => {
+            let bf_str = load(file);
+            let out_path = match out {
+                Some(out) => out.clone(),
+                None => PathBuf::from(
+                    out_name(file, std::env::consts::EXE_EXTENSION).unwrap_or("a".into()),
+                ),
+            };
+            let options = BuildOptions {
+                cc: cc.options(),
+                cflags: cflags.split_whitespace().map(String::from).collect(),
+            };
+            let report = match bf_str.build(&out_path, &opt.pipeline(), &tape.config(), &options) {
+                Ok(report) => report,
+                Err(err) => {
+                    eprintln!("error: {}: {}", out_path.display(), err);
+                    process::exit(1);
+                }
+            };
+            if report.dead_loops > 0 {
+                println!("Removed {} dead loop(s)", report.dead_loops);
+            }
+            println!("Successfully built {:?}", &out_path);
+        }
+        Commands::DumpIr {
+            tape,
+            optimized,
+            level,
+            format,
+            file,
+        } => {
+            let bf_str = load(file);
+            let level = match level {
+                Some(level) => *level,
+                None if *optimized => 3,
+                None => 0,
+            };
+            print!(
+                "{}",
+                bf_str.dump_ir(&Pipeline::level(level), &tape.config(), *format)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{out_name, Cli, Commands};
+    use clap::Parser;
+    use std::path::Path;
+
+    #[test]
+    fn test_opt_level() {
+        let level = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Commands::Run { opt, .. } => opt.level,
+            _ => unreachable!(),
+        };
+        assert_eq!(level(&["bfter", "run", "x.bf"]), 0);
+        assert_eq!(level(&["bfter", "run", "x.bf", "-O"]), 3);
+        assert_eq!(level(&["bfter", "run", "-O2", "x.bf"]), 2);
+        assert_eq!(level(&["bfter", "run", "-O", "1", "x.bf"]), 1);
+        assert_eq!(level(&["bfter", "run", "--opt-level=2", "x.bf"]), 2);
+        assert_eq!(level(&["bfter", "run", "x.bf", "--optimize"]), 3);
+        assert!(Cli::try_parse_from(["bfter", "run", "-O4", "x.bf"]).is_err());
+
+        // an `-O` meant for the C compiler stays as it is
+        let args = ["bfter", "build", "--cflags", "-O", "x.bf"];
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Build { cflags, opt, .. } => {
+                assert_eq!((cflags.as_str(), opt.level), ("-O", 0))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_out_name() {
+        let name = |file: &str, extension: &str| out_name(Path::new(file), extension).unwrap();
+        assert_eq!(name("sample/hello.bf", "c"), "hello.c");
+        assert_eq!(name("a.b.bf", "c"), "a.b.c");
+        assert_eq!(name("a.b", "c"), "a.b.c");
+        assert_eq!(name("hello.bf", ""), "hello");
+        assert_eq!(name("hello", ""), "hello");
     }
 }